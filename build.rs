@@ -0,0 +1,229 @@
+//! Generates the structopt fields for every wasm-opt option listed in wasm-opt.txt,
+//! which is the output of `wasm-opt --help` for the default binaryen version.
+use std::{env, fmt::Write, fs, path::Path};
+
+const SOURCE: &str = "wasm-opt.txt";
+
+/// Set by cargo-wasm itself or by clap, or would write something other than the wasm to the
+/// `--output` file (or stdout): so never passed through
+const SKIPPED: &[&str] = &[
+    "version",
+    "help",
+    "output",
+    "emit-text",
+    "translate-to-fuzz",
+    "metrics",
+    "func-metrics",
+    "print",
+    "print-call-graph",
+    "print-features",
+    "print-full",
+    "print-function-map",
+    "print-minified",
+    "print-stack-ir",
+];
+
+/// The first & last of the optimization passes, which are listed together in wasm-opt.txt
+const FIRST_PASS: &str = "--alignment-lowering";
+const LAST_PASS: &str = "--vacuum";
+
+/// Options taking a value: everything else is a flag
+const VALUES: &[(&str, &str)] = &[
+    ("pass-arg", "Vec<String>"),
+    ("optimize-level", "Option<u32>"),
+    ("shrink-level", "Option<u32>"),
+    ("always-inline-max-function-size", "Option<u32>"),
+    ("flexible-inline-max-function-size", "Option<u32>"),
+    ("one-caller-inline-max-function-size", "Option<u32>"),
+    ("extra-fuzz-command", "Option<String>"),
+    ("input-source-map", "Option<PathBuf>"),
+    ("output-source-map", "Option<PathBuf>"),
+    ("output-source-map-url", "Option<String>"),
+    ("emit-js-wrapper", "Option<PathBuf>"),
+    ("emit-spec-wrapper", "Option<PathBuf>"),
+    ("emit-wasm2c-wrapper", "Option<PathBuf>"),
+];
+
+struct Flag {
+    /// As wasm-opt expects it, e.g. `--enable-simd` or `-O3`
+    arg: String,
+    /// The long name for clap, e.g. `enable-simd` or `O3`
+    long: String,
+    field: String,
+    ty: &'static str,
+    help: String,
+    /// An optimization pass or -O level, so it can be given to --passes
+    pass: bool,
+}
+
+fn parse(line: &str) -> Option<Flag> {
+    let line = line.trim();
+    let mut parts = line.splitn(2, char::is_whitespace);
+    // e.g. `--pass-arg,-pa`: only the first spelling is used
+    let arg = parts.next()?.split(',').next()?.to_string();
+    let help = parts.next().unwrap_or_default().trim().to_string();
+    let long = arg.trim_start_matches('-').to_string();
+    if long.is_empty() || SKIPPED.contains(&long.as_str()) {
+        return None;
+    }
+    let ty = VALUES
+        .iter()
+        .find(|(name, _)| *name == long)
+        .map(|(_, ty)| *ty)
+        .unwrap_or("bool");
+    Some(Flag {
+        field: long.replace('-', "_"),
+        arg,
+        long,
+        ty,
+        help,
+        pass: false,
+    })
+}
+
+/// Marks the passes, which are all in one block, & the -O levels
+fn mark_passes(flags: &mut [Flag]) {
+    let position = |arg: &str| {
+        flags
+            .iter()
+            .position(|f| f.arg == arg)
+            .unwrap_or_else(|| panic!("{} is not in {}", arg, SOURCE))
+    };
+    let (first, last) = (position(FIRST_PASS), position(LAST_PASS));
+    for (i, f) in flags.iter_mut().enumerate() {
+        f.pass = f.ty == "bool" && ((first..=last).contains(&i) || f.arg.starts_with("-O"));
+    }
+}
+
+fn generate(flags: &[Flag]) -> String {
+    let mut out = String::new();
+    out.push_str("/// Every wasm-opt option in wasm-opt.txt: generated by build.rs\n");
+    out.push_str("#[derive(StructOpt, Debug, Default, Clone)]\n#[allow(non_snake_case)]\n");
+    out.push_str("pub(crate) struct WasmOptFlags {\n");
+    for f in flags {
+        writeln!(out, "    #[doc = {:?}]", f.help).unwrap();
+        // Repeated rather than taking several values, as wasm-opt does
+        let repeated = if f.ty.starts_with("Vec") {
+            ", number_of_values = 1"
+        } else {
+            ""
+        };
+        writeln!(out, "    #[structopt(long = {:?}{})]", f.long, repeated).unwrap();
+        writeln!(out, "    pub(crate) {}: {},", f.field, f.ty).unwrap();
+    }
+    out.push_str("}\n\nimpl WasmOptFlags {\n");
+
+    out.push_str(
+        "    /// The options that were set as wasm-opt args, in the order of wasm-opt.txt\n",
+    );
+    out.push_str("    pub(crate) fn args(&self) -> Vec<String> {\n");
+    out.push_str("        let mut args = Vec::new();\n");
+    for f in flags {
+        match f.ty {
+            "bool" => writeln!(
+                out,
+                "        if self.{} {{ args.push(String::from({:?})); }}",
+                f.field, f.arg
+            ),
+            "Vec<String>" => writeln!(
+                out,
+                "        for v in &self.{} {{ args.push(String::from({:?})); args.push(v.clone()); }}",
+                f.field, f.arg
+            ),
+            "Option<PathBuf>" => writeln!(
+                out,
+                "        if let Some(v) = &self.{} {{ args.push(String::from({:?})); args.push(v.display().to_string()); }}",
+                f.field, f.arg
+            ),
+            _ => writeln!(
+                out,
+                "        if let Some(v) = &self.{} {{ args.push(String::from({:?})); args.push(v.to_string()); }}",
+                f.field, f.arg
+            ),
+        }
+        .unwrap();
+    }
+    out.push_str("        args\n    }\n\n");
+
+    out.push_str("    /// Fill in anything not set from `other`. Setting either of --enable-X & --disable-X\n");
+    out.push_str("    /// ignores both in `other`.\n");
+    out.push_str("    pub(crate) fn merge(&mut self, mut other: WasmOptFlags) {\n");
+    for enable in flags.iter().filter(|f| f.long.starts_with("enable-")) {
+        let disable = format!("disable-{}", &enable.long["enable-".len()..]);
+        if let Some(disable) = flags.iter().find(|f| f.long == disable) {
+            writeln!(
+                out,
+                "        if self.{0} || self.{1} {{ other.{0} = false; other.{1} = false; }}",
+                enable.field, disable.field
+            )
+            .unwrap();
+        }
+    }
+    for f in flags {
+        match f.ty {
+            "bool" => writeln!(out, "        self.{0} |= other.{0};", f.field),
+            "Vec<String>" => writeln!(
+                out,
+                "        if self.{0}.is_empty() {{ self.{0} = other.{0}; }}",
+                f.field
+            ),
+            _ => writeln!(
+                out,
+                "        if self.{0}.is_none() {{ self.{0} = other.{0}; }}",
+                f.field
+            ),
+        }
+        .unwrap();
+    }
+    out.push_str("    }\n\n");
+
+    out.push_str("    /// Turns off an option by its long name, e.g. `debuginfo`. False if there is no such option.\n");
+    out.push_str(
+        "    pub(crate) fn unset(&mut self, option: &str) -> bool {\n        match option {\n",
+    );
+    for f in flags {
+        let reset = match f.ty {
+            "bool" => format!("self.{} = false", f.field),
+            "Vec<String>" => format!("self.{}.clear()", f.field),
+            _ => format!("self.{} = None", f.field),
+        };
+        writeln!(out, "            {:?} => {},", f.long, reset).unwrap();
+    }
+    out.push_str("            _ => return false,\n        }\n        true\n    }\n\n");
+
+    out.push_str("    pub(crate) fn clear_levels(&mut self) {\n");
+    for f in flags.iter().filter(|f| f.arg.starts_with("-O")) {
+        writeln!(out, "        self.{} = false;", f.field).unwrap();
+    }
+    out.push_str("    }\n\n");
+
+    out.push_str("    /// The -O optimization levels that were set\n");
+    out.push_str("    pub(crate) fn levels(&self) -> Vec<&'static str> {\n        let mut levels = Vec::new();\n");
+    for f in flags.iter().filter(|f| f.arg.starts_with("-O")) {
+        writeln!(
+            out,
+            "        if self.{} {{ levels.push({:?}); }}",
+            f.field, f.arg
+        )
+        .unwrap();
+    }
+    out.push_str("        levels\n    }\n}\n\n");
+
+    out.push_str("/// Every optimization pass & -O level, as wasm-opt expects it\n");
+    out.push_str("pub(crate) const PASSES: &[&str] = &[\n");
+    for f in flags.iter().filter(|f| f.pass) {
+        writeln!(out, "    {:?},", f.arg).unwrap();
+    }
+    out.push_str("];\n");
+    out
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", SOURCE);
+    println!("cargo:rerun-if-changed=build.rs");
+    let source = fs::read_to_string(SOURCE).expect("wasm-opt.txt is missing");
+    let mut flags: Vec<Flag> = source.lines().filter_map(parse).collect();
+    mark_passes(&mut flags);
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("wasm_opt_flags.rs");
+    fs::write(out, generate(&flags)).unwrap();
+}
@@ -0,0 +1,301 @@
+use crate::{error::Error, wasm_opt::WasmOpt, Opt, SubCmds, WasmTarget};
+use cargo_metadata::{Metadata, Package};
+use serde::Deserialize;
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use structopt::StructOpt;
+
+const KEY: &str = "wasm";
+const PACKAGE: &str = "[package.metadata.wasm]";
+const WORKSPACE: &str = "[workspace.metadata.wasm]";
+
+/// The `[package.metadata.wasm]` or `[workspace.metadata.wasm]` table. Flags on the command line
+/// take precedence, then the package's table, then the workspace's.
+/// Each flag the config can turn on has a `--no-` flag to turn it off on the command line.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Config {
+    target: Option<String>,
+    /// Relative to the Cargo.toml it is in
+    out_dir: Option<PathBuf>,
+    typescript: Option<bool>,
+    weak_refs: Option<bool>,
+    reference_types: Option<bool>,
+    no_demangle: Option<bool>,
+    no_wasm_opt: Option<bool>,
+    /// Set to run wasm-opt without giving the subcommand
+    wasm_opt: Option<WasmOptConfig>,
+    /// The directory paths are relative to
+    #[serde(skip)]
+    dir: PathBuf,
+    #[serde(skip)]
+    manifest: PathBuf,
+    #[serde(skip)]
+    table: &'static str,
+}
+
+/// The same options as `cargo wasm build wasm-opt`
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct WasmOptConfig {
+    passes: Option<Vec<String>>,
+    release_passes: Option<Vec<String>>,
+    debug_passes: Option<Vec<String>>,
+    skip_debug: Option<bool>,
+    binaryen_version: Option<String>,
+    mirror: Option<String>,
+    archive: Option<PathBuf>,
+    sha256: Option<String>,
+    wasm_opt_path: Option<PathBuf>,
+    /// Any other wasm-opt flags e.g. `["--enable-simd", "--shrink-level", "2"]`
+    flags: Option<Vec<String>>,
+}
+
+impl WasmOptConfig {
+    /// Parsed as if given on the command line, so it is checked the same way
+    fn to_wasm_opt(&self, dir: &Path) -> Result<WasmOpt, String> {
+        let mut args = vec![String::from("wasm-opt")];
+        let mut list = |name: &str, values: &Option<Vec<String>>| {
+            if let Some(values) = values {
+                for v in values {
+                    args.push(format!("--{}={}", name, v));
+                }
+            }
+        };
+        list("passes", &self.passes);
+        list("release-passes", &self.release_passes);
+        list("debug-passes", &self.debug_passes);
+        let mut value = |name: &str, value: Option<String>| {
+            if let Some(value) = value {
+                args.push(format!("--{}={}", name, value));
+            }
+        };
+        let path = |p: &Option<PathBuf>| p.as_ref().map(|p| dir.join(p).display().to_string());
+        value("binaryen-version", self.binaryen_version.clone());
+        value("mirror", self.mirror.clone());
+        value("archive", path(&self.archive));
+        value("sha256", self.sha256.clone());
+        value("wasm-opt-path", path(&self.wasm_opt_path));
+        if self.skip_debug == Some(true) {
+            args.push(String::from("--skip-debug"));
+        }
+        args.extend(self.flags.iter().flatten().cloned());
+        WasmOpt::from_iter_safe(args).map_err(|e| e.message)
+    }
+}
+
+impl Config {
+    /// Empty when there is no table
+    fn read(
+        value: &serde_json::Value,
+        manifest: &Path,
+        table: &'static str,
+    ) -> Result<Self, Error> {
+        let mut config = match value.get(KEY) {
+            Some(value) => Config::deserialize(value).map_err(|e| Error::Config {
+                manifest: manifest.to_path_buf(),
+                table,
+                message: e.to_string(),
+            })?,
+            None => Config::default(),
+        };
+        config.manifest = manifest.to_path_buf();
+        config.table = table;
+        config.dir = manifest.parent().unwrap_or(Path::new("")).to_path_buf();
+        Ok(config)
+    }
+
+    /// `[package.metadata.wasm]`: only used for that package
+    pub(crate) fn package(package: &Package) -> Result<Self, Error> {
+        Self::read(&package.metadata, &package.manifest_path, PACKAGE)
+    }
+
+    /// `[workspace.metadata.wasm]`: used for every package
+    pub(crate) fn workspace(metadata: &Metadata) -> Result<Self, Error> {
+        let root = metadata.workspace_root.join("Cargo.toml");
+        Self::read(&metadata.workspace_metadata, &root, WORKSPACE)
+    }
+
+    fn error(&self, message: String) -> Error {
+        Error::Config {
+            manifest: self.manifest.clone(),
+            table: self.table,
+            message,
+        }
+    }
+
+    /// `opt` with anything it doesn't set taken from the config
+    pub(crate) fn apply(&self, opt: &Opt) -> Result<Opt, Error> {
+        let mut opt = opt.clone();
+        if opt.target.is_none() {
+            if let Some(target) = &self.target {
+                opt.target = Some(WasmTarget::from_str(target).map_err(|e| self.error(e))?);
+            }
+        }
+        if opt.out_dir.is_none() {
+            opt.out_dir = self
+                .out_dir
+                .as_ref()
+                .map(|d| self.dir.join(d).display().to_string());
+        }
+        flag(&mut opt.typescript, opt.no_typescript, self.typescript);
+        flag(&mut opt.weak_refs, opt.no_weak_refs, self.weak_refs);
+        flag(
+            &mut opt.reference_types,
+            opt.no_reference_types,
+            self.reference_types,
+        );
+        flag(&mut opt.no_demangle, opt.demangle, self.no_demangle);
+        // The wasm-opt subcommand on the command line turns it back on
+        let cli_wasm_opt = opt.subs.is_some();
+        flag(&mut opt.no_wasm_opt, cli_wasm_opt, self.no_wasm_opt);
+
+        if let Some(wasm_opt) = &self.wasm_opt {
+            let config = wasm_opt.to_wasm_opt(&self.dir).map_err(|e| self.error(e))?;
+            match &mut opt.subs {
+                Some(SubCmds::WASM_OPT(cli)) => cli.merge(config),
+                // Turned off with --no-wasm-opt or no-wasm-opt
+                None if opt.no_wasm_opt => {}
+                None => opt.subs = Some(SubCmds::WASM_OPT(config)),
+            }
+        }
+        Ok(opt)
+    }
+}
+
+/// A flag the command line neither turned `on` nor `off` is taken from the config
+fn flag(on: &mut bool, off: bool, config: Option<bool>) {
+    if !*on && !off {
+        *on = config == Some(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::metadata_in;
+
+    fn config(toml: serde_json::Value) -> Result<Config, Error> {
+        let metadata = serde_json::json!({ "wasm": toml });
+        Config::read(&metadata, Path::new("/project/Cargo.toml"), PACKAGE)
+    }
+
+    #[test]
+    fn cli_takes_precedence() {
+        let config = config(serde_json::json!({
+            "target": "webpack",
+            "out-dir": "www/js",
+            "typescript": true,
+            "wasm-opt": {
+                "passes": ["dce", "-Oz"],
+                "flags": ["--enable-simd"],
+                "archive": "vendor/binaryen.tar.gz"
+            }
+        }))
+        .unwrap();
+
+        let opt = config.apply(&Opt::default()).unwrap();
+        assert!(matches!(opt.target, Some(WasmTarget::Webpack)));
+        assert_eq!(opt.out_dir.as_deref(), Some("/project/www/js"));
+        assert!(opt.typescript);
+        assert!(!opt.weak_refs);
+        let wasm_opt = match &opt.subs {
+            Some(SubCmds::WASM_OPT(wasm_opt)) => wasm_opt,
+            None => panic!("wasm-opt should be enabled by the config"),
+        };
+        assert_eq!(
+            wasm_opt.pass_args(&opt),
+            vec!["--enable-simd", "--dce", "-Oz"]
+        );
+
+        let cli = Opt::from_iter(&[
+            "build",
+            "--target",
+            "web",
+            "--out-dir",
+            "dist",
+            "wasm-opt",
+            "--O3",
+        ]);
+        let opt = config.apply(&cli).unwrap();
+        assert!(matches!(opt.target, Some(WasmTarget::Web)));
+        assert_eq!(opt.out_dir.as_deref(), Some("dist"));
+        match &opt.subs {
+            Some(SubCmds::WASM_OPT(wasm_opt)) => {
+                // The level on the command line replaces the passes in the config
+                assert!(wasm_opt.validate().is_ok());
+                assert_eq!(wasm_opt.pass_args(&opt), vec!["--enable-simd", "-O3"])
+            }
+            None => panic!("wasm-opt should be enabled"),
+        }
+    }
+
+    #[test]
+    fn cli_turns_off_config() {
+        let config = config(serde_json::json!({
+            "typescript": true,
+            "weak-refs": true,
+            "no-demangle": true,
+            "no-wasm-opt": true,
+            "wasm-opt": {
+                "skip-debug": true,
+                "flags": ["--enable-simd", "--debuginfo", "--shrink-level", "2"]
+            }
+        }))
+        .unwrap();
+        let apply = |args: &[&str]| {
+            let cli = Opt::from_iter(std::iter::once("build").chain(args.iter().copied()));
+            config.apply(&cli).unwrap()
+        };
+
+        let opt = apply(&[]);
+        assert!(opt.typescript && opt.weak_refs && opt.no_demangle);
+        assert!(opt.wasm_opt().is_none());
+        assert!(opt.validate().is_ok());
+
+        let opt = apply(&["--no-typescript", "--no-weak-refs", "--demangle"]);
+        assert!(!opt.typescript && !opt.weak_refs && !opt.no_demangle);
+
+        let opt = apply(&[
+            "wasm-opt",
+            "--no-skip-debug",
+            "--disable-simd",
+            "--unset",
+            "debuginfo",
+            "--unset=--shrink-level",
+        ]);
+        let wasm_opt = opt.wasm_opt().unwrap();
+        assert!(wasm_opt.runs_for(&opt));
+        assert_eq!(wasm_opt.pass_args(&opt), vec!["--disable-simd", "-O1"]);
+
+        let unknown = Opt::from_iter(&["build", "wasm-opt", "--unset", "nope"]);
+        assert!(unknown.validate().is_err());
+    }
+
+    #[test]
+    fn invalid_config() {
+        assert!(matches!(
+            config(serde_json::json!({ "nope": 1 })),
+            Err(Error::Config { .. })
+        ));
+        let bad_pass =
+            config(serde_json::json!({ "wasm-opt": { "passes": ["not-a-pass"] } })).unwrap();
+        match bad_pass.apply(&Opt::default()) {
+            Err(e) => assert!(e.to_string().contains("/project/Cargo.toml")),
+            Ok(_) => panic!("an unknown pass should be rejected"),
+        }
+        assert!(config(serde_json::json!(null)).is_err());
+    }
+
+    #[test]
+    fn workspace_config() {
+        let metadata = metadata_in("test_crates/mixed/workspace");
+        let config = Config::workspace(&metadata).unwrap();
+        assert!(config.typescript == Some(true));
+        assert_eq!(config.manifest, metadata.workspace_root.join("Cargo.toml"));
+        let web = metadata.packages.iter().find(|p| p.name == "web").unwrap();
+        assert!(Config::package(web).unwrap().typescript.is_none());
+    }
+}
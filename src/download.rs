@@ -0,0 +1,133 @@
+use crate::error::Error;
+use flate2::read::GzDecoder;
+use log::{error, info};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tar::Archive;
+
+/// GET the url, retrying once on failure. A non 2xx response is an error.
+pub(crate) fn fetch(url: &str) -> Result<Vec<u8>, Error> {
+    info!("Downloading: {}", url);
+    let client = reqwest::blocking::Client::new();
+    // TODO: How many retries?
+    let get = || {
+        client
+            .get(url)
+            .send()
+            .and_then(|r| r.error_for_status())
+            .and_then(|r| r.bytes())
+    };
+    let data = match get() {
+        Ok(data) => data,
+        Err(e) => {
+            error!("First request failed with: '{}'. Retrying...", e);
+            get().map_err(|source| Error::Download {
+                url: url.to_string(),
+                source,
+            })?
+        }
+    };
+    Ok(data.to_vec())
+}
+
+/// Lowercase hex SHA-256 of `data`
+pub(crate) fn sha256(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Fails unless `data` has the `expected` SHA-256, which is compared case insensitively
+pub(crate) fn verify_sha256(url: &str, data: &[u8], expected: &str) -> Result<(), Error> {
+    let actual = sha256(data);
+    if actual.eq_ignore_ascii_case(expected.trim()) {
+        Ok(())
+    } else {
+        Err(Error::Checksum {
+            url: url.to_string(),
+            expected: expected.trim().to_string(),
+            actual,
+        })
+    }
+}
+
+/// Unpack the entries of a .tar.gz for which `keep` is true, keeping their paths & permissions.
+/// Returns the paths of the unpacked files, relative to `out_dir`.
+pub(crate) fn unpack_matching(
+    data: &[u8],
+    out_dir: &Path,
+    keep: impl Fn(&Path) -> bool,
+) -> Result<Vec<PathBuf>, Error> {
+    std::fs::create_dir_all(out_dir).map_err(|e| Error::io(out_dir, e))?;
+    let mut archive = Archive::new(GzDecoder::new(data));
+    let mut unpacked = Vec::new();
+    for entry in archive.entries().map_err(|e| Error::io(out_dir, e))? {
+        let mut entry = entry.map_err(|e| Error::io(out_dir, e))?;
+        let path = entry
+            .path()
+            .map_err(|e| Error::io(out_dir, e))?
+            .into_owned();
+        if entry.header().entry_type().is_dir() || !keep(&path) {
+            continue;
+        }
+        // unpack_in refuses paths that would escape out_dir
+        if entry
+            .unpack_in(out_dir)
+            .map_err(|e| Error::io(out_dir.join(&path), e))?
+        {
+            unpacked.push(path);
+        }
+    }
+    Ok(unpacked)
+}
+
+/// Unpack only the entries of a .tar.gz with one of the given file names, dropping any
+/// directories they are in. Returns the unpacked paths, which keep their permissions.
+pub(crate) fn unpack_files(
+    data: &[u8],
+    file_names: &[&str],
+    out_dir: &Path,
+) -> Result<Vec<PathBuf>, Error> {
+    std::fs::create_dir_all(out_dir).map_err(|e| Error::io(out_dir, e))?;
+    let mut archive = Archive::new(GzDecoder::new(data));
+    let mut unpacked = Vec::new();
+    for entry in archive.entries().map_err(|e| Error::io(out_dir, e))? {
+        let mut entry = entry.map_err(|e| Error::io(out_dir, e))?;
+        let path = entry.path().map_err(|e| Error::io(out_dir, e))?;
+        let file_name = match path.file_name().and_then(|f| f.to_str()) {
+            Some(f) if file_names.contains(&f) => f.to_string(),
+            _ => continue,
+        };
+        let dst = out_dir.join(file_name);
+        entry.unpack(&dst).map_err(|e| Error::io(&dst, e))?;
+        unpacked.push(dst);
+    }
+    Ok(unpacked)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+
+    /// A .tar.gz of (path, contents, mode) entries
+    pub(crate) fn tarball(entries: &[(&str, &[u8], u32)]) -> Vec<u8> {
+        let mut tarball = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+        for (path, contents, mode) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(*mode);
+            header.set_cksum();
+            tarball.append_data(&mut header, path, *contents).unwrap();
+        }
+        tarball.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn checks_sha256() {
+        let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(sha256(b"abc"), abc);
+        assert!(verify_sha256("url", b"abc", abc).is_ok());
+        assert!(verify_sha256("url", b"abc", &abc.to_uppercase()).is_ok());
+        let e = verify_sha256("url", b"abd", abc).unwrap_err();
+        assert!(matches!(e, Error::Checksum { .. }));
+    }
+}
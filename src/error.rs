@@ -0,0 +1,228 @@
+use std::{
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+};
+
+#[derive(Debug)]
+pub(crate) enum CommandError {
+    /// The process could not be started
+    Spawn(String, io::Error),
+    /// The process ran but exited unsuccessfully
+    Failed(String, ExitStatus),
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::Spawn(cmd, e) => write!(f, "Unable to run {}\n{}", cmd, e),
+            CommandError::Failed(cmd, status) => write!(f, "{} failed with {}", cmd, status),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CommandError::Spawn(_, e) => Some(e),
+            CommandError::Failed(_, _) => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Error {
+    /// `cargo metadata` could not be run or parsed
+    Metadata(cargo_metadata::Error),
+    /// A cargo command run for a package failed
+    Toolchain {
+        package: String,
+        source: CommandError,
+    },
+    /// A tool such as wasm-bindgen-cli or wasm-opt could not be installed
+    Install {
+        tool: String,
+        version: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    Download {
+        url: String,
+        source: reqwest::Error,
+    },
+    /// A download did not match its known SHA-256
+    Checksum {
+        url: String,
+        expected: String,
+        actual: String,
+    },
+    /// wasm-bindgen failed to build the js glue code for a package
+    Bindgen {
+        package: String,
+        source: CommandError,
+    },
+    WasmOpt {
+        wasm: PathBuf,
+        source: CommandError,
+    },
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Watch(notify::Error),
+    Serve {
+        port: u16,
+        source: io::Error,
+    },
+    TestsFailed {
+        failed: usize,
+        total: usize,
+    },
+    /// Options that can't be used together, or are invalid together
+    Args(String),
+    /// The `[package.metadata.wasm]` or `[workspace.metadata.wasm]` table is invalid
+    Config {
+        manifest: PathBuf,
+        table: &'static str,
+        message: String,
+    },
+    /// Two packages would write the same js & wasm files
+    Conflict {
+        name: String,
+        packages: (String, String),
+        out_dir: PathBuf,
+    },
+    /// cargo-wasm must be run through cargo, which sets `CARGO`
+    NoCargo(std::env::VarError),
+}
+
+impl Error {
+    pub(crate) fn io(path: impl AsRef<Path>, source: io::Error) -> Self {
+        Error::Io {
+            path: path.as_ref().to_path_buf(),
+            source,
+        }
+    }
+
+    pub(crate) fn install(
+        tool: &str,
+        version: &str,
+        source: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> Self {
+        Error::Install {
+            tool: tool.to_string(),
+            version: version.to_string(),
+            source: source.into(),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Metadata(e) => write!(f, "Unable to read cargo metadata\n{}", e),
+            Error::Toolchain { package, source } => {
+                write!(f, "cargo failed for {}\n{}", package, source)
+            }
+            Error::Install {
+                tool,
+                version,
+                source,
+            } => write!(f, "Unable to install {} {}\n{}", tool, version, source),
+            Error::Download { url, source } => {
+                write!(f, "Unable to download {}\n{}", url, source)
+            }
+            Error::Checksum {
+                url,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Checksum mismatch for {}\nexpected sha256: {}\n  actual sha256: {}",
+                url, expected, actual
+            ),
+            Error::Bindgen { package, source } => {
+                write!(
+                    f,
+                    "Unable to build js glue code for {}\n{}",
+                    package, source
+                )
+            }
+            Error::WasmOpt { wasm, source } => {
+                write!(
+                    f,
+                    "Failed to run wasm-opt for {}\n{}",
+                    wasm.display(),
+                    source
+                )
+            }
+            Error::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            Error::Watch(e) => write!(f, "Unable to watch for changes\n{}", e),
+            Error::Serve { port, source } => {
+                write!(f, "Unable to serve on port {}\n{}", port, source)
+            }
+            Error::TestsFailed { failed, total } => {
+                write!(f, "{} of {} packages failed their tests", failed, total)
+            }
+            Error::Args(e) => write!(f, "Invalid arguments: {}", e),
+            Error::Config {
+                manifest,
+                table,
+                message,
+            } => write!(
+                f,
+                "Invalid {} in {}\n{}",
+                table,
+                manifest.display(),
+                message
+            ),
+            Error::Conflict {
+                name,
+                packages: (first, second),
+                out_dir,
+            } => write!(
+                f,
+                "{} and {} would both write {}.js to {}: set a different out-dir for one of them",
+                first,
+                second,
+                name,
+                out_dir.display()
+            ),
+            Error::NoCargo(e) => write!(f, "CARGO {}: cargo-wasm should be run as `cargo wasm`", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Metadata(e) => Some(e),
+            Error::Toolchain { source, .. } => Some(source),
+            Error::Install { source, .. } => Some(source.as_ref()),
+            Error::Download { source, .. } => Some(source),
+            Error::Checksum { .. } => None,
+            Error::Bindgen { source, .. } => Some(source),
+            Error::WasmOpt { source, .. } => Some(source),
+            Error::Io { source, .. } => Some(source),
+            Error::Watch(e) => Some(e),
+            Error::Serve { source, .. } => Some(source),
+            Error::TestsFailed { .. } => None,
+            Error::Args(_) => None,
+            Error::Config { .. } => None,
+            Error::Conflict { .. } => None,
+            Error::NoCargo(e) => Some(e),
+        }
+    }
+}
+
+impl From<cargo_metadata::Error> for Error {
+    fn from(e: cargo_metadata::Error) -> Self {
+        Error::Metadata(e)
+    }
+}
+
+impl From<notify::Error> for Error {
+    fn from(e: notify::Error) -> Self {
+        Error::Watch(e)
+    }
+}
@@ -0,0 +1,272 @@
+use crate::{download, error::Error};
+use fs2::FileExt;
+use log::{error, info, trace, warn};
+use std::{
+    fs::{File, OpenOptions},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+/// An exclusive lock on `<dir>.lock`, shared between threads & processes. Released on drop.
+struct InstallLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl InstallLock {
+    fn acquire(dir: &Path) -> Result<Self, Error> {
+        let mut path = dir.as_os_str().to_owned();
+        path.push(".lock");
+        let path = PathBuf::from(path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| Error::io(parent, e))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|e| Error::io(&path, e))?;
+        if file.try_lock_exclusive().is_err() {
+            info!("Waiting for lock on {}", path.display());
+            file.lock_exclusive().map_err(|e| Error::io(&path, e))?;
+        }
+        Ok(Self { file, path })
+    }
+}
+
+impl Drop for InstallLock {
+    fn drop(&mut self) {
+        if let Err(e) = self.file.unlock() {
+            error!("Failed to unlock {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Installs a tool into `dir` unless `installed` already holds for it.
+/// `install` is given an empty temporary directory next to `dir`, which is only renamed into
+/// place once it succeeds: so other processes never see a partial install.
+pub(crate) fn install_once(
+    dir: &Path,
+    installed: impl Fn(&Path) -> bool,
+    install: impl FnOnce(&Path) -> Result<(), Error>,
+) -> Result<bool, Error> {
+    if installed(dir) {
+        return Ok(false);
+    }
+    let _lock = InstallLock::acquire(dir)?;
+    // Another process may have finished while waiting for the lock
+    if installed(dir) {
+        return Ok(false);
+    }
+
+    let parent = dir.parent().unwrap_or_else(|| Path::new("."));
+    let tmp = tempfile::Builder::new()
+        .prefix(".tmp-")
+        .tempdir_in(parent)
+        .map_err(|e| Error::io(parent, e))?;
+    install(tmp.path())?;
+
+    // Anything left here is from an old or interrupted install
+    if dir.exists() {
+        std::fs::remove_dir_all(dir).map_err(|e| Error::io(dir, e))?;
+    }
+    // Once renamed there is nothing left for `tmp` to clean up on drop
+    std::fs::rename(tmp.path(), dir).map_err(|e| Error::io(dir, e))?;
+    Ok(true)
+}
+
+const MANIFEST: &str = "MANIFEST";
+
+/// Records the sha256 of each installed file in `dir`, one `<sha256>  <path>` per line as `sha256sum` does.
+/// `files` are relative to `dir`.
+pub(crate) fn write_manifest(dir: &Path, files: &[PathBuf]) -> Result<(), Error> {
+    let mut manifest = String::new();
+    for file in files {
+        let path = dir.join(file);
+        let data = std::fs::read(&path).map_err(|e| Error::io(&path, e))?;
+        let file = file.to_string_lossy().replace('\\', "/");
+        manifest.push_str(&format!("{}  {}\n", download::sha256(&data), file));
+    }
+    let path = dir.join(MANIFEST);
+    std::fs::write(&path, manifest).map_err(|e| Error::io(path, e))
+}
+
+/// Whether every file in the manifest of `dir` is still as it was installed
+pub(crate) fn verify_manifest(dir: &Path) -> bool {
+    let manifest = match std::fs::read_to_string(dir.join(MANIFEST)) {
+        Ok(manifest) => manifest,
+        Err(_) => return false,
+    };
+    manifest.lines().all(|line| match line.split_once("  ") {
+        Some((sha256, file)) => match std::fs::read(dir.join(file)) {
+            Ok(data) => download::sha256(&data) == sha256,
+            Err(_) => false,
+        },
+        None => false,
+    })
+}
+
+/// Whether `bin --version` prints one of `versions` as a word, e.g. `wasm-bindgen 0.2.68 (b2e2e4dd7)`
+fn reports_version(bin: &Path, versions: &[&str]) -> bool {
+    match Command::new(bin)
+        .arg("--version")
+        .stderr(Stdio::null())
+        .output()
+    {
+        Ok(output) if output.status.success() => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            trace!("{} --version: {}", bin.display(), stdout.trim());
+            stdout
+                .split_whitespace()
+                .map(|w| w.trim_matches(|c| c == '(' || c == ')'))
+                .any(|w| versions.contains(&w))
+        }
+        _ => false,
+    }
+}
+
+/// Every `name` found in the directories on `PATH`
+fn on_path(name: &str) -> Vec<PathBuf> {
+    let name = format!("{}{}", name, std::env::consts::EXE_SUFFIX);
+    std::env::var_os("PATH")
+        .map(|paths| {
+            std::env::split_paths(&paths)
+                .map(|dir| dir.join(&name))
+                .filter(|bin| bin.is_file())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// An already installed tool reporting one of `versions`, so nothing needs to be installed.
+/// The `explicit` path is tried first, then each `name` on `PATH`.
+/// `usable` can reject a candidate, e.g. one missing a companion binary.
+pub(crate) fn find_system_tool(
+    explicit: Option<&Path>,
+    name: &str,
+    versions: &[&str],
+    usable: impl Fn(&Path) -> bool,
+) -> Option<PathBuf> {
+    if let Some(explicit) = explicit {
+        if usable(explicit) && reports_version(explicit, versions) {
+            return Some(explicit.to_path_buf());
+        }
+        warn!(
+            "{} is not {} {}: ignoring it",
+            explicit.display(),
+            name,
+            versions[0]
+        );
+    }
+    on_path(name)
+        .into_iter()
+        .find(|bin| usable(bin) && reports_version(bin, versions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[test]
+    fn installs_once_across_threads() {
+        let dir = Path::new("target/test-install-once/tool");
+        std::fs::remove_dir_all(dir.parent().unwrap()).unwrap_or(());
+        let bin = |dir: &Path| dir.join("bin");
+        let installs = Arc::new(AtomicUsize::new(0));
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let installs = installs.clone();
+                std::thread::spawn(move || {
+                    install_once(
+                        dir,
+                        |d| bin(d).exists(),
+                        |tmp| {
+                            installs.fetch_add(1, Ordering::SeqCst);
+                            // Slow enough for the others to be waiting on the lock
+                            std::thread::sleep(std::time::Duration::from_millis(50));
+                            std::fs::write(bin(tmp), "").map_err(|e| Error::io(tmp, e))
+                        },
+                    )
+                    .unwrap()
+                })
+            })
+            .collect();
+        let installed = handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .filter(|i| *i)
+            .count();
+
+        assert_eq!(installed, 1);
+        assert_eq!(installs.load(Ordering::SeqCst), 1);
+        assert!(bin(dir).exists());
+    }
+
+    #[test]
+    fn verifies_manifest() {
+        let dir = Path::new("target/test-manifest");
+        std::fs::remove_dir_all(dir).unwrap_or(());
+        std::fs::create_dir_all(dir.join("bin")).unwrap();
+        std::fs::write(dir.join("bin/tool"), "tool").unwrap();
+        assert!(!verify_manifest(dir));
+
+        write_manifest(dir, &[PathBuf::from("bin/tool")]).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join(MANIFEST)).unwrap(),
+            format!("{}  bin/tool\n", download::sha256(b"tool"))
+        );
+        assert!(verify_manifest(dir));
+
+        std::fs::write(dir.join("bin/tool"), "changed").unwrap();
+        assert!(!verify_manifest(dir));
+        std::fs::remove_file(dir.join("bin/tool")).unwrap();
+        assert!(!verify_manifest(dir));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn finds_matching_system_tool() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = Path::new("target/test-system-tool");
+        std::fs::create_dir_all(dir).unwrap();
+        let tool = dir.join("tool");
+        std::fs::write(&tool, "#!/bin/sh\necho 'tool 0.2.68 (b2e2e4dd7)'\n").unwrap();
+        std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let find = |versions: &[&str], usable: bool| {
+            find_system_tool(Some(&tool), "cargo-wasm-test-tool", versions, |_| usable)
+        };
+        assert_eq!(find(&["0.2.68"], true), Some(tool.clone()));
+        assert_eq!(find(&["0.2.67"], true), None);
+        assert_eq!(find(&["version_68", "0.2.68"], true), Some(tool.clone()));
+        assert_eq!(find(&["0.2.68"], false), None);
+    }
+
+    #[test]
+    fn failed_install_leaves_nothing() {
+        let dir = Path::new("target/test-install-failed/tool");
+        std::fs::remove_dir_all(dir.parent().unwrap()).unwrap_or(());
+        let result = install_once(
+            dir,
+            |d| d.exists(),
+            |tmp| {
+                std::fs::write(tmp.join("partial"), "").unwrap();
+                Err(Error::install("tool", "0.0.0", "failed"))
+            },
+        );
+        assert!(result.is_err());
+        assert!(!dir.exists());
+        // Only the lock file remains
+        let left: Vec<_> = std::fs::read_dir(dir.parent().unwrap())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(left, vec![std::ffi::OsString::from("tool.lock")]);
+    }
+}
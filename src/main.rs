@@ -1,12 +1,20 @@
+mod config;
+mod download;
+mod error;
+mod install;
+mod serve;
+mod target_features;
 mod templates;
 mod wasm_opt;
-use cargo_metadata::{self, Error, Metadata, Package};
-use log::{error, info, LevelFilter};
+mod watch;
+use cargo_metadata::{self, CargoOpt, DependencyKind, Metadata, Node, Package, PackageId};
+use config::Config;
+use error::{CommandError, Error};
+use log::{error, info, warn, LevelFilter};
 use std::{
-    collections::BTreeSet,
-    path::PathBuf,
-    process::Command,
-    process::{ExitStatus, Stdio},
+    collections::{BTreeSet, HashMap, HashSet},
+    path::{Component, Path, PathBuf},
+    process::{Command, Stdio},
     str::FromStr,
     sync::Arc,
 };
@@ -16,22 +24,137 @@ use wasm_opt::WasmOpt;
 const WASM32_UNKNOWN_UNKNOWN: &str = "wasm32-unknown-unknown";
 const WASM_BINDGEN: &str = "wasm-bindgen";
 const WASM_BINDGEN_CLI: &str = "wasm-bindgen-cli";
+const CDYLIB: &str = "cdylib";
+const WASM_BINDGEN_TEST_RUNNER: &str = "wasm-bindgen-test-runner";
+const TEST_RUNNER_ENV: &str = "CARGO_TARGET_WASM32_UNKNOWN_UNKNOWN_RUNNER";
+const WASM_BINDGEN_RELEASES: &str = "https://github.com/rustwasm/wasm-bindgen/releases/download";
+const TOOLS_DIR_ENV: &str = "CARGO_WASM_CACHE_DIR";
+const TOOLS_DIR: &str = "cargo-wasm";
 const OUT_DIR: &str = "dist/js";
+const SERVE_DIR: &str = "dist";
 
-fn path_to_cli(wasm_bindgen_version: &str) -> PathBuf {
-    let mut path = PathBuf::from("target");
-    path.push(WASM_BINDGEN_CLI);
+/// Tools are shared by every project so they survive `cargo clean`: `CARGO_WASM_CACHE_DIR` if set,
+/// otherwise `cargo-wasm` in the user's cache dir (`$XDG_CACHE_HOME` on linux).
+/// Only falls back to the target dir when neither exist.
+fn tools_dir(target_dir: &Path) -> PathBuf {
+    resolve_tools_dir(
+        std::env::var_os(TOOLS_DIR_ENV).map(PathBuf::from),
+        dirs::cache_dir(),
+        target_dir,
+    )
+}
+
+fn resolve_tools_dir(
+    env_dir: Option<PathBuf>,
+    cache_dir: Option<PathBuf>,
+    target_dir: &Path,
+) -> PathBuf {
+    match (env_dir, cache_dir) {
+        (Some(dir), _) if !dir.as_os_str().is_empty() => dir,
+        (_, Some(dir)) => dir.join(TOOLS_DIR),
+        _ => target_dir.join(TOOLS_DIR),
+    }
+}
+
+/// Absolute, with any `.` & `..` resolved without touching the file system: so two spellings of
+/// a directory that may not exist yet compare equal
+fn normalize(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    let cwd = std::env::current_dir().unwrap_or_default();
+    for component in cwd.join(path).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normal.pop();
+            }
+            c => normal.push(c),
+        }
+    }
+    normal
+}
+
+fn path_to_cli(tools_dir: &Path, wasm_bindgen_version: &str) -> PathBuf {
+    let mut path = tools_dir.join(WASM_BINDGEN_CLI);
     path.push(wasm_bindgen_version);
     path.push("bin");
-    path.push(WASM_BINDGEN);
+    path.push(format!("{}{}", WASM_BINDGEN, std::env::consts::EXE_SUFFIX));
     path
 }
 
-fn run_command(mut cmd: Command, quiet: bool) -> std::io::Result<ExitStatus> {
-    if quiet {
-        cmd.stderr(Stdio::null()).status()
+/// Installed alongside wasm-bindgen, both by `cargo install` & in the prebuilt releases
+fn test_runner_next_to(cli: &Path) -> PathBuf {
+    cli.with_file_name(format!(
+        "{}{}",
+        WASM_BINDGEN_TEST_RUNNER,
+        std::env::consts::EXE_SUFFIX
+    ))
+}
+
+/// A wasm-bindgen of this version that is already installed, e.g. by a distro package.
+/// Only used if it has a wasm-bindgen-test-runner next to it.
+fn system_wasm_bindgen(explicit: Option<&Path>, wasm_bindgen_version: &str) -> Option<PathBuf> {
+    install::find_system_tool(explicit, WASM_BINDGEN, &[wasm_bindgen_version], |bin| {
+        test_runner_next_to(bin).is_file()
+    })
+}
+
+/// The targets wasm-bindgen publishes prebuilt releases for
+fn wasm_bindgen_release_target() -> Option<&'static str> {
+    match (std::env::consts::ARCH, std::env::consts::OS) {
+        ("x86_64", "linux") => Some("x86_64-unknown-linux-musl"),
+        ("x86_64", "macos") => Some("x86_64-apple-darwin"),
+        ("x86_64", "windows") => Some("x86_64-pc-windows-msvc"),
+        _ => None,
+    }
+}
+
+/// Unpacks wasm-bindgen & wasm-bindgen-test-runner from a release tarball into `bin_dir`
+fn download_wasm_bindgen_cli(
+    releases_url: &str,
+    release_target: &str,
+    wasm_bindgen_version: &str,
+    bin_dir: &Path,
+) -> Result<(), Error> {
+    let url = format!(
+        "{0}/{1}/{2}-{1}-{3}.tar.gz",
+        releases_url, wasm_bindgen_version, WASM_BINDGEN, release_target
+    );
+    let data = download::fetch(&url)?;
+    let file_names: Vec<String> = [WASM_BINDGEN, WASM_BINDGEN_TEST_RUNNER]
+        .iter()
+        .map(|b| format!("{}{}", b, std::env::consts::EXE_SUFFIX))
+        .collect();
+    let file_names: Vec<&str> = file_names.iter().map(|f| f.as_str()).collect();
+    let unpacked = download::unpack_files(&data, &file_names, bin_dir)?;
+    if unpacked.len() == file_names.len() {
+        Ok(())
     } else {
-        cmd.status()
+        Err(Error::install(
+            WASM_BINDGEN_CLI,
+            wasm_bindgen_version,
+            format!("{} does not contain {}", url, file_names.join(" & ")),
+        ))
+    }
+}
+
+fn run_command(mut cmd: Command, quiet: bool) -> Result<(), CommandError> {
+    let cmd_line = format!("{:?}", cmd);
+    if quiet {
+        cmd.stderr(Stdio::null());
+    }
+    match cmd.status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(CommandError::Failed(cmd_line, status)),
+        Err(e) => Err(CommandError::Spawn(cmd_line, e)),
+    }
+}
+
+/// --profile, otherwise --release: both are left out for the dev profile
+fn profile_args(release: bool, profile: Option<&str>) -> Vec<&str> {
+    match profile {
+        Some(profile) => vec!["--profile", profile],
+        None if release => vec!["--release"],
+        None => vec![],
     }
 }
 
@@ -39,106 +162,190 @@ fn run_command(mut cmd: Command, quiet: bool) -> std::io::Result<ExitStatus> {
 struct Cargo(String);
 
 impl Cargo {
-    fn install_wasm_bindgen_cli(&self, wasm_bindgen_version: &str, quiet: bool) {
-        let mut path = path_to_cli(wasm_bindgen_version);
-        if path.exists() {
+    fn install_wasm_bindgen_cli(
+        &self,
+        tools_dir: &Path,
+        wasm_bindgen_version: &str,
+        quiet: bool,
+    ) -> Result<(), Error> {
+        // <tools_dir>/wasm-bindgen-cli/<version>
+        let mut dir = path_to_cli(tools_dir, wasm_bindgen_version);
+        dir.pop();
+        dir.pop();
+        let installed = |dir: &Path| {
+            let cli =
+                dir.join("bin")
+                    .join(format!("{}{}", WASM_BINDGEN, std::env::consts::EXE_SUFFIX));
+            cli.exists() && test_runner_next_to(&cli).exists()
+        };
+        let fresh = install::install_once(&dir, installed, |tmp| {
+            self.install_wasm_bindgen_cli_into(tmp, wasm_bindgen_version, quiet)
+        })?;
+        if fresh {
+            info!("{} installed at: {}", WASM_BINDGEN_CLI, dir.display());
+        } else {
             info!(
                 "{} {} already installed",
                 WASM_BINDGEN_CLI, wasm_bindgen_version
             );
-        } else {
-            path.pop();
-            path.pop();
-            info!("Installing {}: {}", WASM_BINDGEN_CLI, wasm_bindgen_version);
-            let mut cmd = Command::new(&self.0);
-            cmd.args(&[
-                "install",
-                "--root",
-                &path.display().to_string(),
-                "--version",
+        }
+        Ok(())
+    }
+
+    /// Installs into `root`/bin, which is expected to be empty
+    fn install_wasm_bindgen_cli_into(
+        &self,
+        root: &Path,
+        wasm_bindgen_version: &str,
+        quiet: bool,
+    ) -> Result<(), Error> {
+        let bin = root.join("bin");
+        if let Some(release_target) = wasm_bindgen_release_target() {
+            match download_wasm_bindgen_cli(
+                WASM_BINDGEN_RELEASES,
+                release_target,
                 wasm_bindgen_version,
-                "--",
-                WASM_BINDGEN_CLI,
-            ]);
-            match run_command(cmd, quiet) {
-                Ok(_) => info!("{} installed at: {}", WASM_BINDGEN_CLI, path.display()),
-                Err(e) => error!("Unable to install {}\n{}", WASM_BINDGEN_CLI, e),
-            };
+                &bin,
+            ) {
+                Ok(_) => return Ok(()),
+                Err(e) => {
+                    warn!("{}\nFalling back to cargo install", e);
+                    // cargo install refuses to overwrite binaries it did not install
+                    std::fs::remove_dir_all(&bin).unwrap_or(());
+                }
+            }
+        } else {
+            info!(
+                "No prebuilt {} for this platform: using cargo install",
+                WASM_BINDGEN_CLI
+            );
         }
+
+        info!("Installing {}: {}", WASM_BINDGEN_CLI, wasm_bindgen_version);
+        let mut cmd = Command::new(&self.0);
+        cmd.args([
+            "install",
+            "--root",
+            &root.display().to_string(),
+            "--version",
+            wasm_bindgen_version,
+            "--",
+            WASM_BINDGEN_CLI,
+        ]);
+        run_command(cmd, quiet)
+            .map_err(|e| Error::install(WASM_BINDGEN_CLI, wasm_bindgen_version, e))
     }
 
-    fn build_wasm32_unknown_unknown(&self, package_name: &str, opt: &Opt) {
+    fn build_wasm32_unknown_unknown(
+        &self,
+        package_name: &str,
+        features: &[String],
+        opt: &Opt,
+    ) -> Result<(), Error> {
         info!("Building {} for {}", WASM32_UNKNOWN_UNKNOWN, package_name);
         let mut cmd = Command::new(&self.0);
-        cmd.args(&[
+        cmd.args([
             "build",
             "--package",
             package_name,
             "--target",
             WASM32_UNKNOWN_UNKNOWN,
         ]);
-        if opt.release {
-            cmd.arg("--release");
-        }
-        match run_command(cmd, opt.quiet) {
-            Ok(_) => info!("Built {} for {}", WASM32_UNKNOWN_UNKNOWN, package_name),
-            Err(e) => error!(
-                "Unable run cargo to build {} for {}\n{}",
-                WASM32_UNKNOWN_UNKNOWN, package_name, e
-            ),
+        cmd.args(features);
+        cmd.args(profile_args(opt.release, opt.profile.as_deref()));
+        run_command(cmd, opt.quiet).map_err(|source| Error::Toolchain {
+            package: package_name.to_string(),
+            source,
+        })?;
+        info!("Built {} for {}", WASM32_UNKNOWN_UNKNOWN, package_name);
+        Ok(())
+    }
+
+    /// Runs `cargo test` for wasm32-unknown-unknown with wasm-bindgen-test-runner set as the runner.
+    /// Unless the tests are configured with `run_in_browser` the runner will use node.
+    fn test_wasm32_unknown_unknown(
+        &self,
+        package_name: &str,
+        runner: &Path,
+        features: &[String],
+        opt: &TestOpt,
+    ) -> Result<(), Error> {
+        info!("Testing {} for {}", package_name, WASM32_UNKNOWN_UNKNOWN);
+        let mut cmd = Command::new(&self.0);
+        cmd.args([
+            "test",
+            "--package",
+            package_name,
+            "--target",
+            WASM32_UNKNOWN_UNKNOWN,
+        ]);
+        cmd.args(features);
+        cmd.args(profile_args(opt.release, opt.profile.as_deref()));
+        if !opt.test_args.is_empty() {
+            cmd.arg("--").args(&opt.test_args);
         }
+        cmd.env(TEST_RUNNER_ENV, runner);
+        run_command(cmd, opt.quiet).map_err(|source| Error::Toolchain {
+            package: package_name.to_string(),
+            source,
+        })
     }
 
     // TODO: is cargo new the best way here? Using for now since it gets the local author.
-    fn new_template_project(&self, name: &str, target: &WasmTarget, quiet: bool) {
+    fn new_template_project(
+        &self,
+        name: &str,
+        target: &WasmTarget,
+        quiet: bool,
+    ) -> Result<(), Error> {
+        let write = |path: PathBuf, contents: &str| {
+            std::fs::write(&path, contents).map_err(|e| Error::io(path, e))
+        };
+
         let mut path = PathBuf::from(name);
         let cargo_toml = path.join("Cargo.toml");
         if cargo_toml.exists() {
-            error!(
-                "destination `{}` already exists",
-                path.canonicalize().unwrap().display()
-            )
+            let e = std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "destination already exists",
+            );
+            Err(Error::io(path, e))
         } else {
             let mut cmd = Command::new(&self.0);
-            cmd.args(&["new", "--lib", name]);
-
-            match run_command(cmd, quiet) {
-                Ok(_) => {}
-                Err(e) => error!("Failed to run cargo new:\n{}", e),
-            }
+            cmd.args(["new", "--lib", name]);
+            run_command(cmd, quiet).map_err(|source| Error::Toolchain {
+                package: name.to_string(),
+                source,
+            })?;
 
-            let ct = std::fs::read_to_string(&cargo_toml).unwrap();
-            std::fs::write(
+            let ct = std::fs::read_to_string(&cargo_toml).map_err(|e| Error::io(&cargo_toml, e))?;
+            write(
                 cargo_toml,
-                ct.replace("[dependencies]", templates::DEPENDENCIES),
-            )
-            .unwrap();
+                &ct.replace("[dependencies]", templates::DEPENDENCIES),
+            )?;
 
-            let lib = path.join("src/lib.rs");
-            std::fs::write(lib, templates::LIB_RS).unwrap();
+            write(path.join("src/lib.rs"), templates::LIB_RS)?;
 
             let gitignore = path.join(".gitignore");
             match target {
                 WasmTarget::Web => {
-                    std::fs::write(gitignore, templates::GITIGNORE).unwrap();
+                    write(gitignore, templates::GITIGNORE)?;
                 }
                 WasmTarget::Rollup => {
-                    std::fs::write(gitignore, templates::NODE_GITIGNORE).unwrap();
-                    std::fs::write(path.join("rollup.config.js"), templates::ROLLUP_TEMPLATE)
-                        .unwrap();
-                    std::fs::write(path.join("package.json"), templates::ROLLUP_PACKAGE_JSON)
-                        .unwrap();
+                    write(gitignore, templates::NODE_GITIGNORE)?;
+                    write(path.join("rollup.config.js"), templates::ROLLUP_TEMPLATE)?;
+                    write(path.join("package.json"), templates::ROLLUP_PACKAGE_JSON)?;
                 }
                 WasmTarget::Webpack => {
-                    std::fs::write(gitignore, templates::NODE_GITIGNORE).unwrap();
+                    write(gitignore, templates::NODE_GITIGNORE)?;
                 }
             }
 
             path.push("dist");
-            std::fs::create_dir_all(&path).unwrap();
+            std::fs::create_dir_all(&path).map_err(|e| Error::io(&path, e))?;
 
             path.push("index.html");
-            std::fs::write(path, templates::make_html(name, target)).unwrap();
+            write(path, &templates::make_html(name, target))
         }
     }
 }
@@ -147,6 +354,8 @@ impl Cargo {
 struct PackageInfo {
     package: Package,
     wasm_bindgen_version: String,
+    /// From its `[package.metadata.wasm]`
+    config: Config,
 }
 
 impl PackageInfo {
@@ -154,36 +363,77 @@ impl PackageInfo {
         self.package.name.replace("-", "_")
     }
 
-    /// Only return a package if it uses wasm-bindgen
+    fn package_dir(&self) -> &Path {
+        self.package
+            .manifest_path
+            .parent()
+            .expect("manifest path is a file")
+    }
+
+    /// Only return a package if it is a cdylib & wasm-bindgen is in its own dependency graph.
+    /// Dev & build dependencies are not followed since they never end up in the wasm.
     fn new(metadata: &Metadata, package: Package) -> Option<Self> {
-        metadata
-            .packages
+        let is_cdylib = package
+            .targets
             .iter()
-            .find_map(|d| {
-                if d.name == WASM_BINDGEN {
-                    Some(d.version.to_string())
-                } else {
-                    None
-                }
-            })
-            .map(|wasm_bindgen_version| Self {
-                package,
-                wasm_bindgen_version,
-            })
+            .any(|t| t.crate_types.iter().any(|c| c == CDYLIB));
+        if !is_cdylib {
+            return None;
+        }
+
+        let nodes: HashMap<&PackageId, &Node> = metadata
+            .resolve
+            .as_ref()?
+            .nodes
+            .iter()
+            .map(|n| (&n.id, n))
+            .collect();
+        let mut seen = HashSet::new();
+        let mut stack = vec![&package.id];
+        while let Some(id) = stack.pop() {
+            if !seen.insert(id) {
+                continue;
+            }
+            let dep = &metadata[id];
+            if dep.name == WASM_BINDGEN {
+                return Some(Self {
+                    wasm_bindgen_version: dep.version.to_string(),
+                    package,
+                    config: Config::default(),
+                });
+            }
+            if let Some(node) = nodes.get(id) {
+                stack.extend(
+                    node.deps
+                        .iter()
+                        .filter(|d| d.dep_kinds.iter().any(|k| k.kind == DependencyKind::Normal))
+                        .map(|d| &d.pkg),
+                );
+            }
+        }
+        None
     }
 
-    fn build_wasm_js(&self, opt: &Opt) -> Result<PathBuf, ()> {
-        let mut cmd = Command::new(path_to_cli(&self.wasm_bindgen_version));
-        let source_wasm = format!(
-            "./target/{}/{}/{}.wasm",
-            WASM32_UNKNOWN_UNKNOWN,
-            if opt.release { "release" } else { "debug" },
-            self.get_package_name()
-        );
-        cmd.arg(source_wasm);
+    /// The wasm file built by cargo
+    fn source_wasm(&self, target_dir: &Path, opt: &Opt) -> PathBuf {
+        let mut path = target_dir.join(WASM32_UNKNOWN_UNKNOWN);
+        path.push(opt.profile_dir());
+        path.push(format!("{}.wasm", self.get_package_name()));
+        path
+    }
+
+    fn build_wasm_js(
+        &self,
+        cli: &Path,
+        target_dir: &Path,
+        out_dir: &Path,
+        opt: &Opt,
+    ) -> Result<(), Error> {
+        let mut cmd = Command::new(cli);
+        cmd.arg(self.source_wasm(target_dir, opt));
 
         let target = opt.target.as_ref().unwrap_or_default().as_ref();
-        cmd.args(&["--target", target]);
+        cmd.args(["--target", target]);
 
         if !opt.typescript {
             cmd.arg("--no-typescript");
@@ -201,22 +451,16 @@ impl PackageInfo {
             cmd.arg("--no-demangle");
         }
 
-        let mut out_wasm = PathBuf::new();
-        out_wasm.push(opt.out_dir.as_deref().unwrap_or_else(|| OUT_DIR));
-        cmd.args(&["--out-dir", &out_wasm.display().to_string()]);
+        cmd.arg("--out-dir").arg(out_dir);
 
         info!("Building js glue code for {}", self.get_package_name(),);
 
-        match run_command(cmd, opt.quiet) {
-            Ok(_) => {
-                info!("js glue built for {}", self.get_package_name());
-                Ok(out_wasm)
-            }
-            Err(e) => {
-                error!("unable to build js glue code:\n{}", e);
-                Err(())
-            }
-        }
+        run_command(cmd, opt.quiet).map_err(|source| Error::Bindgen {
+            package: self.package.name.clone(),
+            source,
+        })?;
+        info!("js glue built for {}", self.get_package_name());
+        Ok(())
     }
 }
 
@@ -224,90 +468,286 @@ impl PackageInfo {
 struct BindgenPackages {
     packages: Vec<PackageInfo>,
     cargo: Cargo,
+    /// Respects `CARGO_TARGET_DIR` & `build.target-dir`
+    target_dir: PathBuf,
+    /// Where wasm-bindgen-cli & wasm-opt are installed
+    tools_dir: PathBuf,
+    workspace_root: PathBuf,
+    /// The name of every workspace member, whether or not it uses wasm-bindgen
+    members: Vec<String>,
+    /// From `[workspace.metadata.wasm]`
+    config: Config,
 }
 
 impl BindgenPackages {
-    fn new(cargo: Cargo) -> Result<Self, Error> {
-        let cmd = cargo_metadata::MetadataCommand::new();
+    /// Resolved with the same features as the build, so the wasm-bindgen versions match it
+    fn new(cargo: Cargo, features: &FeatureSelection) -> Result<Self, Error> {
+        let mut cmd = cargo_metadata::MetadataCommand::new();
+        features.add_to(&mut cmd);
         let metadata = cmd.exec()?;
+        Self::from_metadata(&metadata, cargo)
+    }
+
+    fn from_metadata(metadata: &Metadata, cargo: Cargo) -> Result<Self, Error> {
         let mut packages = Vec::new();
+        let mut members = Vec::new();
         for package in &metadata.packages {
             if metadata.workspace_members.contains(&package.id) {
-                if let Some(p) = PackageInfo::new(&metadata, package.clone()) {
+                members.push(package.name.clone());
+                if let Some(mut p) = PackageInfo::new(metadata, package.clone()) {
+                    p.config = Config::package(package)?;
                     packages.push(p)
                 }
             }
         }
-        Ok(BindgenPackages { packages, cargo })
+        Ok(BindgenPackages {
+            packages,
+            cargo,
+            target_dir: metadata.target_directory.clone(),
+            tools_dir: tools_dir(&metadata.target_directory),
+            workspace_root: metadata.workspace_root.clone(),
+            members,
+            config: Config::workspace(metadata)?,
+        })
+    }
+
+    /// Keeps only the packages chosen with --package, or all but those given to --exclude
+    fn select(&mut self, selection: &PackageSelection) -> Result<(), Error> {
+        for name in selection.package.iter().chain(&selection.exclude) {
+            if !self.members.contains(name) {
+                return Err(Error::Args(format!(
+                    "package `{}` is not a member of the workspace",
+                    name
+                )));
+            }
+        }
+        for name in &selection.package {
+            if !self.packages.iter().any(|p| &p.package.name == name) {
+                return Err(Error::Args(format!(
+                    "package `{}` is not a cdylib using wasm-bindgen",
+                    name
+                )));
+            }
+        }
+        self.packages.retain(|p| {
+            let name = &p.package.name;
+            (selection.workspace
+                || selection.package.is_empty()
+                || selection.package.contains(name))
+                && !selection.exclude.contains(name)
+        });
+        Ok(())
+    }
+
+    /// Either the --out-dir or "dist/js" at the workspace root
+    fn out_dir(&self, opt: &Opt) -> PathBuf {
+        match &opt.out_dir {
+            Some(out_dir) => PathBuf::from(out_dir),
+            None => self.workspace_root.join(OUT_DIR),
+        }
     }
 
-    fn build_wasm32_unknown_unknown(&self, opt: &Opt) {
-        for p in &self.packages {
+    /// Each package with the settings it is built with: the command line, then its
+    /// `[package.metadata.wasm]`, then `[workspace.metadata.wasm]`.
+    /// Two packages writing the same files to the same out-dir are a conflict.
+    fn package_opts<'a>(
+        &self,
+        packages: impl IntoIterator<Item = &'a PackageInfo>,
+        cli: &Opt,
+    ) -> Result<Vec<(&'a PackageInfo, Opt)>, Error> {
+        let mut outputs: HashMap<(PathBuf, String), &str> = HashMap::new();
+        let mut package_opts = Vec::new();
+        for p in packages {
+            let opt = self.config.apply(&p.config.apply(cli)?)?;
+            opt.validate()?;
+            let output = (normalize(&self.out_dir(&opt)), p.get_package_name());
+            if let Some(other) = outputs.insert(output.clone(), &p.package.name) {
+                let (out_dir, name) = output;
+                return Err(Error::Conflict {
+                    name,
+                    packages: (other.to_string(), p.package.name.clone()),
+                    out_dir,
+                });
+            }
+            package_opts.push((p, opt));
+        }
+        Ok(package_opts)
+    }
+
+    /// Stops at the first package that fails to build
+    fn build_wasm32_unknown_unknown(&self, opt: &Opt) -> Result<(), Error> {
+        self.rebuild_wasm32_unknown_unknown(&self.packages.iter().collect::<Vec<_>>(), opt)
+    }
+
+    fn rebuild_wasm32_unknown_unknown(
+        &self,
+        packages: &[&PackageInfo],
+        opt: &Opt,
+    ) -> Result<(), Error> {
+        for p in packages {
+            let features = opt.features.args_for(&p.package.name, &self.members);
             self.cargo
-                .build_wasm32_unknown_unknown(&p.get_package_name(), opt)
+                .build_wasm32_unknown_unknown(&p.package.name, &features, opt)?
         }
+        Ok(())
     }
 
-    // TODO: Move this to the global cargo store?
     // TODO: Cargo will fail if they are different...what should approach be?
     // Could download instead...?
-    fn install_wasm_bindgen_cli(&self, quiet: bool) {
+    fn install_wasm_bindgen_cli(&self, quiet: bool, explicit: Option<&Path>) -> Result<(), Error> {
         let bindgen: BTreeSet<&str> = self
             .packages
             .iter()
             .map(|p| p.wasm_bindgen_version.as_str())
             .collect();
         for bg in bindgen {
-            self.cargo.install_wasm_bindgen_cli(bg, quiet)
+            match system_wasm_bindgen(explicit, bg) {
+                Some(cli) => info!("Using {} {} at: {}", WASM_BINDGEN_CLI, bg, cli.display()),
+                None => self
+                    .cargo
+                    .install_wasm_bindgen_cli(&self.tools_dir, bg, quiet)?,
+            }
         }
+        Ok(())
     }
 
-    fn build_wasm_js(&self, opt: &Opt) {
-        // Must only clean once
-        let mut out_dir = PathBuf::new();
-        out_dir.push(opt.out_dir.as_deref().unwrap_or_else(|| OUT_DIR));
-        if opt.clean {
-            info!("Cleaning out-dir: {}", &out_dir.display());
-            std::fs::remove_dir_all(&out_dir).unwrap_or(());
-        }
+    /// An already installed wasm-bindgen of the right version, otherwise the one cargo-wasm installs
+    fn wasm_bindgen_cli(&self, wasm_bindgen_version: &str, explicit: Option<&Path>) -> PathBuf {
+        system_wasm_bindgen(explicit, wasm_bindgen_version)
+            .unwrap_or_else(|| path_to_cli(&self.tools_dir, wasm_bindgen_version))
+    }
 
-        let wasm_files: Result<Vec<PathBuf>, ()> = self
+    /// Every package is tested, even after a failure
+    fn test(&self, opt: &TestOpt) -> Result<(), Error> {
+        let explicit = opt.wasm_bindgen_path.as_deref();
+        self.install_wasm_bindgen_cli(opt.quiet, explicit)?;
+        let results: Vec<(&str, Result<(), Error>)> = self
             .packages
             .iter()
-            .map(|pi| {
-                pi.build_wasm_js(opt).map(|mut p| {
-                    p.push(format!("{}_bg.wasm", pi.get_package_name()));
-                    p
-                })
+            .map(|p| {
+                let runner =
+                    test_runner_next_to(&self.wasm_bindgen_cli(&p.wasm_bindgen_version, explicit));
+                let features = opt.features.args_for(&p.package.name, &self.members);
+                let result = self.cargo.test_wasm32_unknown_unknown(
+                    &p.package.name,
+                    &runner,
+                    &features,
+                    opt,
+                );
+                (p.package.name.as_str(), result)
             })
             .collect();
 
-        if let Ok(wasm_files) = wasm_files {
-            if let Some(SubCmds::WASM_OPT(wasm_opt)) = &opt.subs {
-                match wasm_opt.try_install() {
-                    Ok(_) => {
-                        for out_wasm in wasm_files {
-                            if let Err(e) = wasm_opt.try_run(&out_wasm, opt) {
-                                error!("Error running wasm-opt: {}", e)
-                            }
-                        }
-                    }
-                    Err(e) => error!("Unable to install wasm-opt:\n{}", e),
+        let mut failed = 0;
+        for (name, result) in &results {
+            match result {
+                Ok(_) => info!("{}: passed", name),
+                Err(e) => {
+                    failed += 1;
+                    error!("{}: failed\n{}", name, e)
                 }
             }
+        }
+        if failed == 0 {
+            Ok(())
+        } else {
+            Err(Error::TestsFailed {
+                failed,
+                total: results.len(),
+            })
+        }
+    }
 
-            // TODO: Here should look for a rollup.config.js file & run it?
-            if let Some(WasmTarget::Rollup) = opt.target {
-                let bootstrap = templates::rollup_bootstrap_js(self.packages.as_slice(), &out_dir);
-                out_dir.push("bootstrap.js");
-                std::fs::write(out_dir, bootstrap).unwrap()
+    fn build_wasm_js(&self, opt: &Opt) -> Result<(), Error> {
+        let package_opts = self.package_opts(&self.packages, opt)?;
+        // Must only clean once, even when packages share an out-dir
+        let out_dirs: BTreeSet<PathBuf> = package_opts
+            .iter()
+            .map(|(_, opt)| normalize(&self.out_dir(opt)))
+            .collect();
+        if opt.clean {
+            for out_dir in &out_dirs {
+                info!("Cleaning out-dir: {}", out_dir.display());
+                std::fs::remove_dir_all(out_dir).unwrap_or(());
             }
         }
+
+        self.build_wasm_files(&package_opts)?;
+
+        // TODO: Here should look for a rollup.config.js file & run it?
+        for out_dir in out_dirs {
+            let rollup = self.rollup_packages(&package_opts, &out_dir);
+            if !rollup.is_empty() {
+                let bootstrap = templates::rollup_bootstrap_js(&rollup, &out_dir);
+                let path = out_dir.join("bootstrap.js");
+                std::fs::write(&path, bootstrap).map_err(|e| Error::io(path, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The rollup packages written to `out_dir`, which share its bootstrap.js
+    fn rollup_packages<'a>(
+        &self,
+        package_opts: &[(&'a PackageInfo, Opt)],
+        out_dir: &Path,
+    ) -> Vec<&'a PackageInfo> {
+        package_opts
+            .iter()
+            .filter(|(_, opt)| {
+                matches!(opt.target, Some(WasmTarget::Rollup))
+                    && normalize(&self.out_dir(opt)) == out_dir
+            })
+            .map(|(p, _)| *p)
+            .collect()
+    }
+
+    /// The js glue code for each package, then wasm-opt on its wasm
+    fn build_wasm_files(&self, package_opts: &[(&PackageInfo, Opt)]) -> Result<(), Error> {
+        for (pi, opt) in package_opts {
+            let out_dir = self.out_dir(opt);
+            let cli =
+                self.wasm_bindgen_cli(&pi.wasm_bindgen_version, opt.wasm_bindgen_path.as_deref());
+            pi.build_wasm_js(&cli, &self.target_dir, &out_dir, opt)?;
+            let wasm = out_dir.join(format!("{}_bg.wasm", pi.get_package_name()));
+            self.run_wasm_opt(&wasm, opt)?;
+        }
+        Ok(())
+    }
+
+    fn run_wasm_opt(&self, wasm: &Path, opt: &Opt) -> Result<(), Error> {
+        if let Some(wasm_opt) = opt.wasm_opt() {
+            if !wasm_opt.runs_for(opt) {
+                info!("Skipping wasm-opt for debug build");
+                return Ok(());
+            }
+            let bin = match wasm_opt.try_install(&self.tools_dir) {
+                Ok(bin) => bin,
+                // Only a release default: the build still works without it
+                Err(e) if opt.subs.is_none() => {
+                    warn!(
+                        "{}\nSkipping wasm-opt: use `cargo wasm build wasm-opt` to require it, or --no-wasm-opt to not try",
+                        e
+                    );
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+            wasm_opt.try_run(&bin, wasm, opt)?;
+        }
+        Ok(())
+    }
+
+    /// Used by watch: only the changed packages are built & the out-dir is never cleaned
+    fn rebuild(&self, packages: &[&PackageInfo], opt: &Opt) -> Result<(), Error> {
+        self.rebuild_wasm32_unknown_unknown(packages, opt)?;
+        let package_opts = self.package_opts(packages.iter().copied(), opt)?;
+        self.build_wasm_files(&package_opts)
     }
 }
 
 // TODO: Need to add package.json for node / deno?
-#[derive(StructOpt)]
+#[derive(StructOpt, Clone)]
 enum WasmTarget {
     Web,
     Rollup,
@@ -355,16 +795,25 @@ impl AsRef<str> for WasmTarget {
 
 // TODO: Look at debug options: should '--debug' be the default when not release?
 // TODO: Add in all cli options
-#[derive(StructOpt, Default)]
+#[derive(StructOpt, Default, Clone)]
 struct Opt {
     /// Compile in release mode
     #[structopt(long, short)]
     release: bool,
 
+    /// Compile with this cargo profile, e.g. a size optimized `[profile.wasm-release]`.
+    /// Custom profiles get the release wasm-opt passes.
+    #[structopt(long, conflicts_with = "release")]
+    profile: Option<String>,
+
     /// Generate typescript files
     #[structopt(long, short)]
     typescript: bool,
 
+    /// Don't generate typescript files, even when [package.metadata.wasm] does
+    #[structopt(long, conflicts_with = "typescript")]
+    no_typescript: bool,
+
     /// Target to compile the js glue code to: web (default), webpack
     #[structopt(long)]
     target: Option<WasmTarget>,
@@ -377,6 +826,10 @@ struct Opt {
     /// Run remove_dir_all on out-dir
     clean: bool,
 
+    /// Watch the src directory & Cargo.toml of each package and rebuild on changes
+    #[structopt(long, short)]
+    watch: bool,
+
     // https://rustwasm.github.io/docs/wasm-bindgen/reference/cli.html#--weak-refs
     /// Enables usage of the TC39 Weak References proposal, ensuring that all Rust memory is eventually deallocated
     /// regardless of whether you're calling free or not. This is off-by-default while we're waiting for support
@@ -384,6 +837,10 @@ struct Opt {
     #[structopt(long)]
     weak_refs: bool,
 
+    /// Turn off --weak-refs when [package.metadata.wasm] turns it on
+    #[structopt(long, conflicts_with = "weak-refs")]
+    no_weak_refs: bool,
+
     // https://rustwasm.github.io/docs/wasm-bindgen/reference/cli.html#--reference-types
     /// Enables usage of the WebAssembly References Types proposal proposal, meaning that the WebAssembly binary
     /// will use externref when importing and exporting functions that work with JsValue. For more information see
@@ -391,27 +848,176 @@ struct Opt {
     #[structopt(long)]
     reference_types: bool,
 
+    /// Turn off --reference-types when [package.metadata.wasm] turns it on
+    #[structopt(long, conflicts_with = "reference-types")]
+    no_reference_types: bool,
+
     // https://rustwasm.github.io/docs/wasm-bindgen/reference/cli.html#--no-demangle
     /// When post-processing the .wasm binary, do not demangle Rust symbols in the "names" custom section.
     #[structopt(long)]
     no_demangle: bool,
 
+    /// Demangle Rust symbols even when [package.metadata.wasm] sets no-demangle
+    #[structopt(long, conflicts_with = "no-demangle")]
+    demangle: bool,
+
+    /// Don't run wasm-opt, which otherwise runs with '-Oz --strip-debug' for release builds
+    #[structopt(long)]
+    no_wasm_opt: bool,
+
+    /// Use this wasm-bindgen, & the wasm-bindgen-test-runner next to it, instead of installing one.
+    /// Only when its version matches: otherwise one on PATH or an installed one is used.
+    #[structopt(long, parse(from_os_str))]
+    wasm_bindgen_path: Option<PathBuf>,
+
     ///  No output printed to stdout
     #[structopt(long, short)]
     quiet: bool,
 
+    #[structopt(flatten)]
+    selection: PackageSelection,
+
+    #[structopt(flatten)]
+    features: FeatureSelection,
+
     #[structopt(subcommand)]
     subs: Option<SubCmds>,
 }
 
-// TODO: Is giant struct a good idea... or should just use a vec? Use a macro to parse the wasm-opt.txt file...
-#[derive(StructOpt, Debug)]
+/// Which packages to build or test: every cdylib using wasm-bindgen by default
+#[derive(StructOpt, Default, Clone, Debug)]
+struct PackageSelection {
+    /// Only these packages
+    #[structopt(long, short, number_of_values = 1, conflicts_with = "workspace")]
+    package: Vec<String>,
+
+    /// All packages in the workspace
+    #[structopt(long)]
+    workspace: bool,
+
+    /// Exclude these packages
+    #[structopt(long, number_of_values = 1, requires = "workspace")]
+    exclude: Vec<String>,
+}
+
+/// Passed through to cargo
+#[derive(StructOpt, Default, Clone, Debug)]
+struct FeatureSelection {
+    /// Space or comma separated list of features to activate. Use `package/feature` for a
+    /// feature of only one package
+    #[structopt(long, number_of_values = 1)]
+    features: Vec<String>,
+
+    /// Activate all available features
+    #[structopt(long)]
+    all_features: bool,
+
+    /// Do not activate the `default` feature
+    #[structopt(long)]
+    no_default_features: bool,
+}
+
+impl FeatureSelection {
+    fn list(&self) -> Vec<&str> {
+        self.features
+            .iter()
+            .flat_map(|f| f.split(|c: char| c == ',' || c.is_whitespace()))
+            .filter(|f| !f.is_empty())
+            .collect()
+    }
+
+    /// The cargo args to build `package`, which is built on its own: so `package/feature` becomes
+    /// `feature`, & features of the other `members` are left out. `dependency/feature` is kept.
+    fn args_for(&self, package: &str, members: &[String]) -> Vec<String> {
+        let same = |a: &str, b: &str| a.replace('-', "_") == b.replace('-', "_");
+        let features: Vec<&str> = self
+            .list()
+            .into_iter()
+            .filter_map(|f| match f.split_once('/') {
+                Some((p, feature)) if same(p, package) => Some(feature),
+                Some((p, _)) if members.iter().any(|m| same(m, p)) => None,
+                _ => Some(f),
+            })
+            .collect();
+        let mut args = Vec::new();
+        if !features.is_empty() {
+            args.push(String::from("--features"));
+            args.push(features.join(","));
+        }
+        if self.all_features {
+            args.push(String::from("--all-features"));
+        }
+        if self.no_default_features {
+            args.push(String::from("--no-default-features"));
+        }
+        args
+    }
+
+    fn add_to(&self, cmd: &mut cargo_metadata::MetadataCommand) {
+        let features = self.list();
+        if !features.is_empty() {
+            cmd.features(CargoOpt::SomeFeatures(
+                features.into_iter().map(String::from).collect(),
+            ));
+        }
+        if self.all_features {
+            cmd.features(CargoOpt::AllFeatures);
+        }
+        if self.no_default_features {
+            cmd.features(CargoOpt::NoDefaultFeatures);
+        }
+    }
+}
+
+impl Opt {
+    /// The directory in the target dir cargo puts the profile's artifacts in
+    fn profile_dir(&self) -> &str {
+        match self.profile.as_deref() {
+            Some("dev") | Some("test") => "debug",
+            Some("release") | Some("bench") => "release",
+            Some(profile) => profile,
+            None if self.release => "release",
+            None => "debug",
+        }
+    }
+
+    /// The wasm-opt settings when it runs: always with the subcommand or a config table,
+    /// & with the profile's passes for release builds
+    fn wasm_opt(&self) -> Option<WasmOpt> {
+        match &self.subs {
+            _ if self.no_wasm_opt => None,
+            Some(SubCmds::WASM_OPT(wasm_opt)) => Some(wasm_opt.clone()),
+            None if self.is_release() => Some(WasmOpt::default()),
+            None => None,
+        }
+    }
+
+    /// Whether the build is optimized as for release, so wasm-opt uses the release passes
+    fn is_release(&self) -> bool {
+        self.profile_dir() != "debug"
+    }
+
+    /// Checked before building anything
+    fn validate(&self) -> Result<(), Error> {
+        match &self.subs {
+            Some(SubCmds::WASM_OPT(_)) if self.no_wasm_opt => Err(Error::Args(String::from(
+                "--no-wasm-opt can't be used with the wasm-opt subcommand",
+            ))),
+            Some(SubCmds::WASM_OPT(wasm_opt)) => wasm_opt.validate(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
 #[allow(non_camel_case_types)]
 enum SubCmds {
     /// Runs wasm-opt https://github.com/WebAssembly/binaryen#wasm-opt.
     /// Its runs as `wasm-opt path_to_wasm --o path_to_wasm [FLAGS]`
-    /// and uses a default optimization of '--O'.
-    /// It will pass through any other relevant flags that are parsed to wasm build: --enable-reference-types
+    /// When no level or --passes are given it runs '-O1' for debug builds & '-Oz --strip-debug' for release.
+    /// Release builds run wasm-opt even without this subcommand, unless --no-wasm-opt is given.
+    /// It will pass through any other relevant flags that are parsed to wasm build: --enable-reference-types,
+    /// & enables the features the wasm was built with (e.g. simd128 from RUSTFLAGS) from its target_features section.
     WASM_OPT(WasmOpt),
 }
 impl Default for SubCmds {
@@ -420,11 +1026,57 @@ impl Default for SubCmds {
     }
 }
 
+#[derive(StructOpt)]
+struct TestOpt {
+    /// Test in release mode
+    #[structopt(long, short)]
+    release: bool,
+
+    /// Test with this cargo profile
+    #[structopt(long, conflicts_with = "release")]
+    profile: Option<String>,
+
+    /// Use the wasm-bindgen-test-runner next to this wasm-bindgen instead of installing one.
+    /// Only when its version matches: otherwise one on PATH or an installed one is used.
+    #[structopt(long, parse(from_os_str))]
+    wasm_bindgen_path: Option<PathBuf>,
+
+    ///  No output printed to stdout
+    #[structopt(long, short)]
+    quiet: bool,
+
+    #[structopt(flatten)]
+    selection: PackageSelection,
+
+    #[structopt(flatten)]
+    features: FeatureSelection,
+
+    /// Arguments passed through to the test runner
+    #[structopt(last = true)]
+    test_args: Vec<String>,
+}
+
+#[derive(StructOpt)]
+struct ServeOpt {
+    /// Port to listen on
+    #[structopt(long, default_value = "8080")]
+    port: u16,
+
+    /// Directory to serve. Default of "./dist"
+    #[structopt(long)]
+    dir: Option<String>,
+
+    #[structopt(flatten)]
+    opt: Opt,
+}
+
 // TODO: Static version of rollup.js for packaging?
 #[derive(StructOpt)]
 enum CargoWasm {
     /// Compile your project to wasm and generate js glue code
     Build(Opt),
+    /// Build your project then serve the dist directory over http
+    Serve(ServeOpt),
     /// Create a template project for loading in the browser
     New {
         /// Project name
@@ -436,15 +1088,16 @@ enum CargoWasm {
         #[structopt(long, short)]
         quiet: bool,
     },
-    // TODO
-    // Run,
-    // Test
+    /// Run wasm-bindgen-test tests in node using wasm-bindgen-test-runner
+    Test(TestOpt),
 }
 
 impl CargoWasm {
     fn quiet(&self) -> bool {
         match self {
             CargoWasm::Build(opt) => opt.quiet,
+            CargoWasm::Serve(serve) => serve.opt.quiet,
+            CargoWasm::Test(opt) => opt.quiet,
             CargoWasm::New {
                 name: _,
                 target: _,
@@ -453,14 +1106,61 @@ impl CargoWasm {
         }
     }
 
-    fn run(&self, cargo: Cargo) {
+    fn run(&self, cargo: Cargo) -> Result<(), Error> {
         match self {
-            CargoWasm::Build(opt) => match BindgenPackages::new(cargo) {
-                Ok(bp) => self.build(bp, &opt),
-                Err(e) => {
-                    error!("{}", e);
+            CargoWasm::Build(opt) => {
+                opt.validate()?;
+                let mut bp = BindgenPackages::new(cargo, &opt.features)?;
+                bp.select(&opt.selection)?;
+                let bp = Arc::new(bp);
+                // Before building anything
+                bp.package_opts(&bp.packages, opt)?;
+                let built = self.build(&bp, opt);
+                if opt.watch {
+                    Self::log_error(built);
+                    self.watch(&bp, opt, || {})
+                } else {
+                    built
+                }
+            }
+            CargoWasm::Serve(serve) => {
+                let opt = &serve.opt;
+                opt.validate()?;
+                let mut bp = BindgenPackages::new(cargo, &opt.features)?;
+                bp.select(&opt.selection)?;
+                let bp = Arc::new(bp);
+                bp.package_opts(&bp.packages, opt)?;
+                let built = self.build(&bp, opt);
+                let reloader = if opt.watch {
+                    Self::log_error(built);
+                    Some(serve::Reloader::default())
+                } else {
+                    built?;
+                    None
+                };
+                let dir = match &serve.dir {
+                    Some(dir) => PathBuf::from(dir),
+                    None => bp.workspace_root.join(SERVE_DIR),
+                };
+                let server =
+                    serve::Server::bind(&dir, serve.port, reloader.clone()).map_err(|source| {
+                        Error::Serve {
+                            port: serve.port,
+                            source,
+                        }
+                    })?;
+                let handler = std::thread::spawn(move || server.run());
+                if let Some(reloader) = reloader {
+                    self.watch(&bp, opt, || reloader.reload())?
                 }
-            },
+                handler.join().unwrap();
+                Ok(())
+            }
+            CargoWasm::Test(opt) => {
+                let mut bp = BindgenPackages::new(cargo, &opt.features)?;
+                bp.select(&opt.selection)?;
+                bp.test(opt)
+            }
             CargoWasm::New {
                 name,
                 target,
@@ -469,23 +1169,41 @@ impl CargoWasm {
         }
     }
 
-    fn build(&self, bindgen_packages: BindgenPackages, opt: &Opt) {
-        let bindgen_packages = Arc::new(bindgen_packages);
-        let bp = Arc::clone(&bindgen_packages);
+    /// When watching a failed build is not fatal: the next change may fix it
+    fn log_error(result: Result<(), Error>) {
+        if let Err(e) = result {
+            error!("{}", e)
+        }
+    }
+
+    fn build(&self, bindgen_packages: &Arc<BindgenPackages>, opt: &Opt) -> Result<(), Error> {
+        let bp = Arc::clone(bindgen_packages);
         let quiet = opt.quiet;
+        let explicit = opt.wasm_bindgen_path.clone();
         // TODO: Is this worth it?
-        let handler = std::thread::spawn(move || {
-            bp.install_wasm_bindgen_cli(quiet);
-        });
-        bindgen_packages.build_wasm32_unknown_unknown(opt);
-        handler.join().unwrap();
-        bindgen_packages.build_wasm_js(opt);
+        let handler =
+            std::thread::spawn(move || bp.install_wasm_bindgen_cli(quiet, explicit.as_deref()));
+        // Always wait for the install so it is never left half done
+        let built = bindgen_packages.build_wasm32_unknown_unknown(opt);
+        let installed = handler.join().unwrap();
+        built?;
+        installed?;
+        bindgen_packages.build_wasm_js(opt)
+    }
+
+    fn watch(
+        &self,
+        bindgen_packages: &BindgenPackages,
+        opt: &Opt,
+        on_rebuild: impl Fn(),
+    ) -> Result<(), Error> {
+        watch::watch(bindgen_packages, opt, on_rebuild)
     }
 }
 
 // TODO: Add verbose options etc: use more trace logging?
 fn main() {
-    let mut args = std::env::args().into_iter();
+    let mut args = std::env::args();
     // Need to skip one arg: .. /.cargo/bin/cargo-wasm for structopt to work?
     args.next();
     let cargo_wasm = CargoWasm::from_iter(args);
@@ -502,41 +1220,485 @@ fn main() {
         .filter_level(log_level)
         .init();
 
-    match std::env::var("CARGO") {
-        Ok(cargo) => cargo_wasm.run(Cargo(cargo)),
-        Err(e) => error!("{}", e),
+    let result = std::env::var("CARGO")
+        .map_err(Error::NoCargo)
+        .and_then(|cargo| cargo_wasm.run(Cargo(cargo)));
+    if let Err(e) = result {
+        error!("{}", e);
+        std::process::exit(1)
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
+
+    /// `cargo metadata` for one of the test_crates
+    pub(crate) fn metadata_in(dir: &str) -> Metadata {
+        cargo_metadata::MetadataCommand::new()
+            .current_dir(dir)
+            .exec()
+            .unwrap()
+    }
+
+    pub(crate) fn packages_from(metadata: &Metadata) -> BindgenPackages {
+        BindgenPackages::from_metadata(metadata, Cargo(String::from("cargo"))).unwrap()
+    }
+
+    pub(crate) fn packages_in(dir: &str) -> BindgenPackages {
+        packages_from(&metadata_in(dir))
+    }
+
+    #[test]
+    fn failed_command_is_an_error() {
+        let cargo = std::env::var("CARGO").unwrap();
+        let mut cmd = Command::new(&cargo);
+        cmd.arg("--version");
+        assert!(run_command(cmd, true).is_ok());
+
+        let mut cmd = Command::new(&cargo);
+        cmd.arg("not-a-cargo-command");
+        assert!(matches!(
+            run_command(cmd, true),
+            Err(CommandError::Failed(_, _))
+        ));
+
+        let cmd = Command::new("not-a-real-program");
+        assert!(matches!(
+            run_command(cmd, true),
+            Err(CommandError::Spawn(_, _))
+        ));
+    }
+    #[test]
+    fn only_cdylib_packages_using_bindgen() {
+        let bp = packages_in("test_crates/mixed/workspace");
+        let packages: Vec<(&str, &str)> = bp
+            .packages
+            .iter()
+            .map(|p| (p.package.name.as_str(), p.wasm_bindgen_version.as_str()))
+            .collect();
+        assert_eq!(packages, vec![("web", "0.2.68")]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn builds_package_by_its_name() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = Path::new("target/test-package-name");
+        std::fs::create_dir_all(dir).unwrap();
+        let args = dir.join("args");
+        let cargo = dir.join("cargo");
+        std::fs::write(
+            &cargo,
+            format!("#!/bin/sh\necho \"$@\" >> {}\n", args.display()),
+        )
+        .unwrap();
+        std::fs::set_permissions(&cargo, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::remove_file(&args).unwrap_or(());
+
+        let mut metadata = metadata_in("test_crates/web/workspace");
+        for p in &mut metadata.packages {
+            if p.name == "crate1" {
+                p.name = String::from("crate-1");
+            }
+        }
+        let mut bp =
+            BindgenPackages::from_metadata(&metadata, Cargo(cargo.display().to_string())).unwrap();
+        bp.packages.retain(|p| p.package.name == "crate-1");
+        bp.build_wasm32_unknown_unknown(&Opt::default()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&args).unwrap(),
+            "build --package crate-1 --target wasm32-unknown-unknown\n"
+        );
+    }
+
+    #[test]
+    fn wasm_opt_by_default_for_release() {
+        let runs = |args: &[&str]| {
+            let opt = Opt::from_iter(std::iter::once("build").chain(args.iter().copied()));
+            opt.wasm_opt().map(|wasm_opt| wasm_opt.pass_args(&opt))
+        };
+        assert_eq!(runs(&[]), None);
+        assert_eq!(
+            runs(&["--release"]),
+            Some(vec![String::from("-Oz"), String::from("--strip-debug")])
+        );
+        assert!(runs(&["--profile", "wasm-release"]).is_some());
+        assert_eq!(runs(&["--release", "--no-wasm-opt"]), None);
+        assert!(
+            Opt::from_iter(&["build", "--no-wasm-opt", "wasm-opt", "--O3"])
+                .validate()
+                .is_err()
+        );
+        assert_eq!(runs(&["wasm-opt"]), Some(vec![String::from("-O1")]));
+    }
+
+    #[test]
+    fn feature_args_per_package() {
+        let opt = Opt::from_iter(&[
+            "build",
+            "--features",
+            "simd, web/console",
+            "--features=crate-1/extra,js-sys/default",
+            "--no-default-features",
+        ]);
+        let members = vec![String::from("web"), String::from("crate-1")];
+        assert_eq!(
+            opt.features.args_for("web", &members),
+            vec![
+                "--features",
+                "simd,console,js-sys/default",
+                "--no-default-features"
+            ]
+        );
+        assert_eq!(
+            opt.features.args_for("crate_1", &members),
+            vec![
+                "--features",
+                "simd,extra,js-sys/default",
+                "--no-default-features"
+            ]
+        );
+        assert!(FeatureSelection::default()
+            .args_for("web", &members)
+            .is_empty());
+    }
+
+    #[test]
+    fn metadata_with_features() {
+        let packages = |features: FeatureSelection| {
+            let mut cmd = cargo_metadata::MetadataCommand::new();
+            cmd.current_dir("test_crates/mixed/workspace");
+            features.add_to(&mut cmd);
+            let metadata = cmd.exec().unwrap();
+            let bp = packages_from(&metadata);
+            let mut names: Vec<String> =
+                bp.packages.iter().map(|p| p.package.name.clone()).collect();
+            names.sort();
+            names
+        };
+        // wasm-bindgen is an optional dependency of `optional`
+        assert_eq!(packages(FeatureSelection::default()), vec!["web"]);
+        let web = FeatureSelection {
+            features: vec![String::from("optional/web")],
+            ..FeatureSelection::default()
+        };
+        assert_eq!(packages(web), vec!["optional", "web"]);
+        let all = FeatureSelection {
+            all_features: true,
+            ..FeatureSelection::default()
+        };
+        assert_eq!(packages(all), vec!["optional", "web"]);
+    }
+
+    #[test]
+    fn selects_packages() {
+        let bp = packages_in("test_crates/web/workspace");
+        let select = |args: &[&str]| {
+            let mut bp = bp.clone();
+            let opt = Opt::from_iter_safe(std::iter::once("build").chain(args.iter().copied()))
+                .map_err(|e| Error::Args(e.message))?;
+            bp.select(&opt.selection)?;
+            let mut names: Vec<String> =
+                bp.packages.iter().map(|p| p.package.name.clone()).collect();
+            names.sort();
+            Ok::<_, Error>(names)
+        };
+
+        assert_eq!(select(&[]).unwrap(), vec!["crate1", "crate2", "workspace"]);
+        assert_eq!(
+            select(&["--workspace"]).unwrap(),
+            vec!["crate1", "crate2", "workspace"]
+        );
+        assert_eq!(select(&["-p", "crate1"]).unwrap(), vec!["crate1"]);
+        assert_eq!(
+            select(&["--package", "crate1", "-p", "crate2"]).unwrap(),
+            vec!["crate1", "crate2"]
+        );
+        assert_eq!(
+            select(&["--workspace", "--exclude", "crate1"]).unwrap(),
+            vec!["crate2", "workspace"]
+        );
+        assert!(matches!(select(&["-p", "nope"]), Err(Error::Args(_))));
+        assert!(matches!(
+            select(&["--exclude", "crate1"]),
+            Err(Error::Args(_))
+        ));
+        assert!(matches!(
+            select(&["-p", "crate1", "--workspace"]),
+            Err(Error::Args(_))
+        ));
+
+        let mut bp = packages_in("test_crates/mixed/workspace");
+        let selection = PackageSelection {
+            package: vec![String::from("native")],
+            ..PackageSelection::default()
+        };
+        let e = bp.select(&selection).unwrap_err();
+        assert!(e.to_string().contains("not a cdylib using wasm-bindgen"));
+    }
+
+    #[test]
+    fn per_package_settings() {
+        let mut metadata = metadata_in("test_crates/web/workspace");
+        metadata.workspace_metadata = serde_json::json!({
+            "wasm": { "out-dir": "www", "wasm-opt": { "passes": ["-O2"] } }
+        });
+        for p in &mut metadata.packages {
+            match p.name.as_str() {
+                "crate1" => {
+                    p.metadata = serde_json::json!({
+                        "wasm": { "target": "webpack", "out-dir": "bundler", "wasm-opt": { "passes": ["-Oz"] } }
+                    })
+                }
+                "crate2" => p.metadata = serde_json::json!({ "wasm": { "typescript": true } }),
+                _ => {}
+            }
+        }
+        let bp = packages_from(&metadata);
+        let root = &metadata.workspace_root;
+        let cli = Opt::default();
+        let package_opts = bp.package_opts(&bp.packages, &cli).unwrap();
+        let get = |name: &str| {
+            let (_, opt) = package_opts
+                .iter()
+                .find(|(p, _)| p.package.name == name)
+                .unwrap();
+            let passes = match &opt.subs {
+                Some(SubCmds::WASM_OPT(wasm_opt)) => wasm_opt.pass_args(opt),
+                None => vec![],
+            };
+            (opt, passes)
+        };
+
+        let (crate1, passes) = get("crate1");
+        assert!(matches!(crate1.target, Some(WasmTarget::Webpack)));
+        assert_eq!(bp.out_dir(crate1), root.join("crate1/bundler"));
+        assert!(!crate1.typescript);
+        assert_eq!(passes, vec!["-Oz"]);
+        let (crate2, passes) = get("crate2");
+        assert!(crate2.target.is_none());
+        assert_eq!(bp.out_dir(crate2), root.join("www"));
+        assert!(crate2.typescript);
+        assert_eq!(passes, vec!["-O2"]);
+
+        // The command line takes precedence over both
+        let cli = Opt::from_iter(&["build", "--out-dir", "dist"]);
+        for (_, opt) in bp.package_opts(&bp.packages, &cli).unwrap() {
+            assert_eq!(bp.out_dir(&opt), PathBuf::from("dist"));
+        }
+    }
+
+    #[test]
+    fn same_output_is_a_conflict() {
+        let mut metadata = metadata_in("test_crates/web/workspace");
+        // Both write crate_1.js
+        for p in &mut metadata.packages {
+            match p.name.as_str() {
+                "crate1" => p.name = String::from("crate-1"),
+                "crate2" => p.name = String::from("crate_1"),
+                _ => {}
+            }
+        }
+        let bp = packages_from(&metadata);
+        let e = match bp.package_opts(&bp.packages, &Opt::default()) {
+            Err(e) => e,
+            Ok(_) => panic!("crate-1 and crate_1 should conflict"),
+        };
+        assert!(matches!(&e, Error::Conflict { name, .. } if name == "crate_1"));
+
+        let cli = Opt::from_iter(&["build", "--out-dir", "dist"]);
+        assert!(bp.package_opts(&bp.packages, &cli).is_err());
+
+        // However the out-dir is written
+        for p in &mut metadata.packages {
+            if p.name == "crate_1" {
+                p.metadata = serde_json::json!({ "wasm": { "out-dir": "./../dist/./js/" } });
+            }
+        }
+        let bp = packages_from(&metadata);
+        assert!(bp.package_opts(&bp.packages, &Opt::default()).is_err());
+
+        // Unless one of them is written somewhere else
+        for p in &mut metadata.packages {
+            if p.name == "crate_1" {
+                p.metadata = serde_json::json!({ "wasm": { "out-dir": "dist/js" } });
+            }
+        }
+        let bp = packages_from(&metadata);
+        assert!(bp.package_opts(&bp.packages, &Opt::default()).is_ok());
+    }
+
+    #[test]
+    fn rollup_packages_share_a_normalized_out_dir() {
+        let mut metadata = metadata_in("test_crates/web/workspace");
+        for p in &mut metadata.packages {
+            match p.name.as_str() {
+                "crate1" => {
+                    p.metadata = serde_json::json!({ "wasm": { "target": "rollup", "out-dir": "../dist/js" } })
+                }
+                "crate2" => p.metadata = serde_json::json!({ "wasm": { "target": "rollup" } }),
+                _ => {}
+            }
+        }
+        let bp = packages_from(&metadata);
+        let package_opts = bp.package_opts(&bp.packages, &Opt::default()).unwrap();
+        let out_dir = normalize(&bp.workspace_root.join("dist/js"));
+        let mut names: Vec<&str> = bp
+            .rollup_packages(&package_opts, &out_dir)
+            .iter()
+            .map(|p| p.package.name.as_str())
+            .collect();
+        names.sort_unstable();
+        assert_eq!(names, vec!["crate1", "crate2"]);
+    }
+
+    #[test]
+    fn paths_from_metadata() {
+        let bp = packages_in("test_crates/mixed/workspace/web");
+        let root = bp.workspace_root.clone();
+        assert!(root.ends_with("test_crates/mixed/workspace"));
+        // Set in .cargo/config.toml
+        assert_eq!(bp.target_dir, root.join("custom-target"));
+        assert_eq!(bp.out_dir(&Opt::default()), root.join("dist/js"));
+        assert!(bp.tools_dir.ends_with(TOOLS_DIR));
+
+        let opt = Opt {
+            release: true,
+            ..Opt::default()
+        };
+        assert_eq!(
+            bp.packages[0].source_wasm(&bp.target_dir, &opt),
+            root.join("custom-target/wasm32-unknown-unknown/release/web.wasm")
+        );
+        let profile = |profile: &str| {
+            let opt = Opt::from_iter(&["build", "--profile", profile]);
+            bp.packages[0].source_wasm(&bp.target_dir, &opt)
+        };
+        let wasm32 = root.join("custom-target/wasm32-unknown-unknown");
+        assert_eq!(
+            profile("wasm-release"),
+            wasm32.join("wasm-release/web.wasm")
+        );
+        assert_eq!(profile("dev"), wasm32.join("debug/web.wasm"));
+        assert_eq!(profile("bench"), wasm32.join("release/web.wasm"));
+        assert!(Opt::from_iter_safe(&["build", "--release", "--profile", "dev"]).is_err());
+    }
+
+    #[test]
+    fn tools_dir_outside_target_dir() {
+        let target_dir = Path::new("target");
+        let cache = || Some(PathBuf::from("/home/me/.cache"));
+        assert_eq!(
+            resolve_tools_dir(Some(PathBuf::from("/tools")), cache(), target_dir),
+            PathBuf::from("/tools")
+        );
+        assert_eq!(
+            resolve_tools_dir(Some(PathBuf::new()), cache(), target_dir),
+            PathBuf::from("/home/me/.cache/cargo-wasm")
+        );
+        assert_eq!(
+            resolve_tools_dir(None, cache(), target_dir),
+            PathBuf::from("/home/me/.cache/cargo-wasm")
+        );
+        assert_eq!(
+            resolve_tools_dir(None, None, target_dir),
+            PathBuf::from("target/cargo-wasm")
+        );
+    }
+
+    #[test]
+    fn new_project_already_exists() {
+        let cargo = Cargo(std::env::var("CARGO").unwrap());
+        let e = cargo
+            .new_template_project("test_crates/web/crate", &WasmTarget::Web, true)
+            .unwrap_err();
+        assert!(matches!(e, Error::Io { .. }));
+        assert!(e.to_string().starts_with("test_crates/web/crate: "));
+    }
+
+    /// Prebuilt releases don't have a .crates.toml so ask the binary instead
+    fn installed_version(tools_dir: &Path, wasm_bindgen_version: &str) -> String {
+        let output = Command::new(path_to_cli(tools_dir, wasm_bindgen_version))
+            .arg("--version")
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    }
+
     #[test]
     fn installs_correct_bindgen_version() {
-        let crates = ".crates.toml";
-        let in_file = |v: &str| format!("wasm-bindgen-cli {}", v);
+        let in_output = |v: &str| format!("wasm-bindgen {}", v);
+        let tools_dir = Path::new("target/cargo-wasm");
 
         let v_1 = "0.2.68";
         let cargo_1 = Cargo(std::env::var("CARGO").unwrap());
-        let mut path_1 = PathBuf::from("target/wasm-bindgen-cli");
-        path_1.push(v_1);
-        path_1.push(crates);
 
         let v_2 = "0.2.67";
         let cargo_2 = cargo_1.clone();
-        let mut path_2 = PathBuf::from("target/wasm-bindgen-cli");
-        path_2.push(v_2);
-        path_2.push(crates);
 
         let handler = std::thread::spawn(move || {
-            cargo_1.install_wasm_bindgen_cli("0.2.68", false);
-            let file_1 = std::fs::read_to_string(path_1).unwrap();
-            assert!(file_1.contains(&in_file(v_1)))
+            cargo_1
+                .install_wasm_bindgen_cli(tools_dir, v_1, false)
+                .unwrap();
+            assert!(installed_version(tools_dir, v_1).contains(&in_output(v_1)));
+            assert!(test_runner_next_to(&path_to_cli(tools_dir, v_1)).exists());
         });
 
-        cargo_2.install_wasm_bindgen_cli("0.2.67", false);
-        let file_2 = std::fs::read_to_string(path_2).unwrap();
-        assert!(file_2.contains(&in_file(v_2)));
+        cargo_2
+            .install_wasm_bindgen_cli(tools_dir, v_2, false)
+            .unwrap();
+        assert!(installed_version(tools_dir, v_2).contains(&in_output(v_2)));
+        assert!(test_runner_next_to(&path_to_cli(tools_dir, v_2)).exists());
         handler.join().unwrap();
     }
+
+    #[test]
+    fn downloads_prebuilt_bindgen() {
+        let version = "0.2.68";
+        let release_target = "test-target";
+        let releases = Path::new("target/test-releases");
+        let release_dir = releases.join(version);
+        std::fs::create_dir_all(&release_dir).unwrap();
+
+        // Laid out like the real releases: everything is in a top level directory
+        let top = format!("wasm-bindgen-{}-{}", version, release_target);
+        let paths: Vec<String> = [WASM_BINDGEN, WASM_BINDGEN_TEST_RUNNER, "wasm2es6js"]
+            .iter()
+            .map(|bin| format!("{}/{}{}", top, bin, std::env::consts::EXE_SUFFIX))
+            .collect();
+        let entries: Vec<(&str, &[u8], u32)> = paths
+            .iter()
+            .map(|path| (path.as_str(), &b"#!/bin/sh\n"[..], 0o755))
+            .collect();
+        let data = download::tests::tarball(&entries);
+        std::fs::write(release_dir.join(format!("{}.tar.gz", top)), data).unwrap();
+
+        let server = serve::Server::bind(releases, 0, None).unwrap();
+        let url = format!("http://{}", server.local_addr().unwrap());
+        std::thread::spawn(move || server.run());
+
+        let tools_dir = Path::new("target/test-downloads");
+        std::fs::remove_dir_all(tools_dir).unwrap_or(());
+        let mut bin_dir = path_to_cli(tools_dir, version);
+        bin_dir.pop();
+        download_wasm_bindgen_cli(&url, release_target, version, &bin_dir).unwrap();
+
+        let exe = |bin: &str| bin_dir.join(format!("{}{}", bin, std::env::consts::EXE_SUFFIX));
+        assert!(exe(WASM_BINDGEN).exists());
+        assert!(exe(WASM_BINDGEN_TEST_RUNNER).exists());
+        assert!(!exe("wasm2es6js").exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(exe(WASM_BINDGEN))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o111, 0o111);
+        }
+
+        let missing = download_wasm_bindgen_cli(&url, release_target, "0.0.0", &bin_dir);
+        assert!(matches!(missing, Err(Error::Download { .. })));
+    }
 }
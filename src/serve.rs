@@ -0,0 +1,279 @@
+use log::{error, info, trace};
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+const NOT_FOUND: &str = "404 Not Found";
+const BAD_REQUEST: &str = "400 Bad Request";
+const METHOD_NOT_ALLOWED: &str = "405 Method Not Allowed";
+const RELOAD_PATH: &str = "/__cargo_wasm/reload";
+
+/// `WebAssembly.instantiateStreaming` will only accept `application/wasm`
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("wasm") => "application/wasm",
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("js") | Some("mjs") => "application/javascript",
+        Some("css") => "text/css",
+        Some("json") | Some("map") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("ico") => "image/x-icon",
+        Some("txt") => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Holds the open Server-Sent Events connections of every page being served
+#[derive(Clone, Default)]
+pub(crate) struct Reloader(Arc<Mutex<Vec<TcpStream>>>);
+
+impl Reloader {
+    fn connect(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: keep-alive\r\n\r\n",
+        )?;
+        stream.flush()?;
+        self.0.lock().unwrap().push(stream);
+        Ok(())
+    }
+
+    /// Tell every connected page to reload, dropping any that have gone away
+    pub(crate) fn reload(&self) {
+        let mut clients = self.0.lock().unwrap();
+        clients.retain(|mut c| {
+            c.write_all(b"data: reload\n\n")
+                .and_then(|_| c.flush())
+                .is_ok()
+        });
+        trace!("Sent reload to {} page(s)", clients.len());
+    }
+}
+
+/// Add the live reload client to the end of a html page
+fn inject_reload_client(html: &str) -> String {
+    let script = format!(
+        r#"<script>new EventSource("{}").onmessage = () => location.reload();</script>"#,
+        RELOAD_PATH
+    );
+    match html.rfind("</body>") {
+        Some(i) => format!("{}{}\n{}", &html[..i], script, &html[i..]),
+        None => format!("{}\n{}", html, script),
+    }
+}
+
+/// A minimal static file server for the `dist` directory
+pub(crate) struct Server {
+    root: PathBuf,
+    listener: TcpListener,
+    reloader: Option<Reloader>,
+}
+
+impl Server {
+    /// When given a `Reloader` the served html pages will reload each time it is triggered
+    pub(crate) fn bind(root: &Path, port: u16, reloader: Option<Reloader>) -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        Ok(Self {
+            root: root.to_path_buf(),
+            listener,
+            reloader,
+        })
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Blocks forever, handling each connection on its own thread
+    pub(crate) fn run(self) {
+        if let Ok(addr) = self.local_addr() {
+            info!("Serving {} at http://{}", self.root.display(), addr);
+        }
+        for stream in self.listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let root = self.root.clone();
+                    let reloader = self.reloader.clone();
+                    std::thread::spawn(move || {
+                        if let Err(e) = handle(stream, &root, reloader.as_ref()) {
+                            trace!("Connection closed: {}", e)
+                        }
+                    });
+                }
+                Err(e) => error!("Failed to accept connection: {}", e),
+            }
+        }
+    }
+}
+
+/// Map a request path onto the served directory, refusing anything that escapes it
+fn resolve(root: &Path, request_path: &str) -> Option<PathBuf> {
+    let request_path = request_path.split(['?', '#']).next()?;
+    let mut path = root.to_path_buf();
+    for component in Path::new(request_path.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(c) => path.push(c),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    if path.is_dir() {
+        path.push("index.html");
+    }
+    Some(path)
+}
+
+fn write_status(stream: &mut TcpStream, status: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        status.len(),
+        status
+    )
+}
+
+fn handle(mut stream: TcpStream, root: &Path, reloader: Option<&Reloader>) -> io::Result<()> {
+    let mut request_line = String::new();
+    let mut reader = BufReader::new(stream.try_clone()?);
+    reader.read_line(&mut request_line)?;
+    // Drain the headers: nothing in them is needed
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (method, request_path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method, path),
+        _ => return write_status(&mut stream, BAD_REQUEST),
+    };
+    trace!("{} {}", method, request_path);
+
+    if method != "GET" && method != "HEAD" {
+        return write_status(&mut stream, METHOD_NOT_ALLOWED);
+    }
+
+    if let Some(reloader) = reloader {
+        if request_path == RELOAD_PATH {
+            return reloader.connect(stream);
+        }
+    }
+
+    let path = match resolve(root, request_path) {
+        Some(path) => path,
+        None => return write_status(&mut stream, BAD_REQUEST),
+    };
+
+    match std::fs::read(&path) {
+        Ok(mut body) => {
+            let content_type = content_type(&path);
+            if reloader.is_some() && content_type.starts_with("text/html") {
+                body = inject_reload_client(&String::from_utf8_lossy(&body)).into_bytes();
+            }
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+                content_type,
+                body.len()
+            )?;
+            if method == "GET" {
+                stream.write_all(&body)?;
+            }
+            stream.flush()
+        }
+        Err(_) => write_status(&mut stream, NOT_FOUND),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wasm_mime_type() {
+        assert_eq!(content_type(Path::new("a_bg.wasm")), "application/wasm");
+        assert_eq!(
+            content_type(Path::new("index.html")),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(content_type(Path::new("a.js")), "application/javascript");
+        assert_eq!(content_type(Path::new("a")), "application/octet-stream");
+    }
+
+    #[test]
+    fn does_not_escape_root() {
+        let root = Path::new("test_crates/web/crate/dist");
+        assert_eq!(
+            resolve(root, "/"),
+            Some(PathBuf::from("test_crates/web/crate/dist/index.html"))
+        );
+        assert_eq!(
+            resolve(root, "/js/crate.js?v=1"),
+            Some(PathBuf::from("test_crates/web/crate/dist/js/crate.js"))
+        );
+        assert_eq!(resolve(root, "/../Cargo.toml"), None);
+    }
+
+    #[test]
+    fn injects_reload_client() {
+        let html = inject_reload_client("<html><body><div></div></body></html>");
+        assert!(html.starts_with("<html><body><div></div><script>"));
+        assert!(html.ends_with("</script>\n</body></html>"));
+        assert!(html.contains(RELOAD_PATH));
+    }
+
+    #[test]
+    fn sends_reload() {
+        let reloader = Reloader::default();
+        let server = Server::bind(Path::new("test_crates"), 0, Some(reloader.clone())).unwrap();
+        let addr = server.local_addr().unwrap();
+        std::thread::spawn(move || server.run());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\n\r\n", RELOAD_PATH).unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        while line != "\r\n" {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+        }
+
+        // Connection is registered on the server thread
+        while reloader.0.lock().unwrap().is_empty() {
+            std::thread::yield_now();
+        }
+        reloader.reload();
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "data: reload\n");
+    }
+
+    #[test]
+    fn serves_wasm() {
+        let server = Server::bind(Path::new("test_crates"), 0, None).unwrap();
+        let addr = server.local_addr().unwrap();
+        std::thread::spawn(move || server.run());
+
+        let url = format!("http://{}/_test.wasm", addr);
+        let response = reqwest::blocking::get(&url).unwrap();
+        assert!(response.status().is_success());
+        assert_eq!(
+            response.headers()["content-type"].to_str().unwrap(),
+            "application/wasm"
+        );
+        let body = response.bytes().unwrap();
+        assert_eq!(
+            &body[..],
+            &std::fs::read("test_crates/_test.wasm").unwrap()[..]
+        );
+
+        let url = format!("http://{}/missing.wasm", addr);
+        assert_eq!(reqwest::blocking::get(&url).unwrap().status(), 404);
+    }
+}
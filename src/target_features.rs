@@ -0,0 +1,140 @@
+//! The `target_features` custom section rustc (via LLVM) adds to the wasm, listing the features
+//! it was built with, e.g. `simd128` from `-C target-feature=+simd128`.
+//! https://github.com/WebAssembly/tool-conventions/blob/main/Linking.md#target-features-section
+
+const SECTION: &str = "target_features";
+const MAGIC: &[u8] = b"\0asm";
+const CUSTOM_SECTION: u8 = 0;
+/// The feature is used, or must be used by everything linked with it
+const USED: u8 = b'+';
+const REQUIRED: u8 = b'=';
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Option<u8> {
+        let (b, rest) = self.data.split_first()?;
+        self.data = rest;
+        Some(*b)
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.data.len() {
+            return None;
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(bytes)
+    }
+
+    /// Unsigned LEB128
+    fn u32(&mut self) -> Option<u32> {
+        let mut result = 0u32;
+        for shift in (0..35).step_by(7) {
+            let b = self.byte()?;
+            result |= u32::from(b & 0x7f).checked_shl(shift)?;
+            if b & 0x80 == 0 {
+                return Some(result);
+            }
+        }
+        None
+    }
+
+    fn name(&mut self) -> Option<&'a str> {
+        let len = self.u32()? as usize;
+        std::str::from_utf8(self.bytes(len)?).ok()
+    }
+}
+
+/// The features enabled by the `target_features` section, or `None` if there isn't one
+pub(crate) fn read(wasm: &[u8]) -> Option<Vec<String>> {
+    let mut reader = Reader { data: wasm };
+    if reader.bytes(4)? != MAGIC {
+        return None;
+    }
+    reader.bytes(4)?;
+    while !reader.data.is_empty() {
+        let id = reader.byte()?;
+        let len = reader.u32()? as usize;
+        let mut section = Reader {
+            data: reader.bytes(len)?,
+        };
+        if id == CUSTOM_SECTION && section.name()? == SECTION {
+            let count = section.u32()?;
+            let mut features = Vec::new();
+            for _ in 0..count {
+                let prefix = section.byte()?;
+                let name = section.name()?;
+                if prefix == USED || prefix == REQUIRED {
+                    features.push(name.to_string());
+                }
+            }
+            return Some(features);
+        }
+    }
+    None
+}
+
+/// The wasm-opt flag enabling an LLVM wasm feature
+pub(crate) fn wasm_opt_flag(feature: &str) -> Option<&'static str> {
+    match feature {
+        "atomics" => Some("--enable-threads"),
+        "bulk-memory" => Some("--enable-bulk-memory"),
+        "exception-handling" => Some("--enable-exception-handling"),
+        "multivalue" => Some("--enable-multivalue"),
+        "mutable-globals" => Some("--enable-mutable-globals"),
+        "nontrapping-fptoint" => Some("--enable-nontrapping-float-to-int"),
+        "reference-types" => Some("--enable-reference-types"),
+        "sign-ext" => Some("--enable-sign-ext"),
+        "simd128" => Some("--enable-simd"),
+        "tail-call" => Some("--enable-tail-call"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn section(id: u8, payload: &[u8]) -> Vec<u8> {
+        let mut section = vec![id, payload.len() as u8];
+        section.extend_from_slice(payload);
+        section
+    }
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut bytes = vec![name.len() as u8];
+        bytes.extend_from_slice(name.as_bytes());
+        bytes
+    }
+
+    #[test]
+    fn reads_target_features() {
+        let mut payload = name(SECTION);
+        payload.push(3);
+        for (prefix, feature) in &[(USED, "simd128"), (b'-', "atomics"), (REQUIRED, "sign-ext")] {
+            payload.push(*prefix);
+            payload.extend(name(feature));
+        }
+        let mut wasm = b"\0asm\x01\0\0\0".to_vec();
+        // An empty type section & another custom section come first
+        wasm.extend(section(1, &[0]));
+        wasm.extend(section(CUSTOM_SECTION, &name("producers")));
+        wasm.extend(section(CUSTOM_SECTION, &payload));
+
+        let features = read(&wasm).unwrap();
+        assert_eq!(features, vec!["simd128", "sign-ext"]);
+        let flags: Vec<&str> = features.iter().filter_map(|f| wasm_opt_flag(f)).collect();
+        assert_eq!(flags, vec!["--enable-simd", "--enable-sign-ext"]);
+    }
+
+    #[test]
+    fn no_target_features() {
+        assert_eq!(read(b"\0asm\x01\0\0\0"), None);
+        assert_eq!(read(b"not wasm"), None);
+        let wasm = std::fs::read("test_crates/_test.wasm").unwrap();
+        assert_eq!(read(&wasm), None);
+    }
+}
@@ -31,7 +31,7 @@ pub(crate) fn make_html(project_name: &str, target: &WasmTarget) -> String {
             project_name
         ),
         WasmTarget::Rollup | WasmTarget::Webpack => {
-            format!(r#"<script src="js/index.js"></script>"#)
+            r#"<script src="js/index.js"></script>"#.to_string()
         }
     };
 
@@ -52,7 +52,7 @@ pub(crate) fn make_html(project_name: &str, target: &WasmTarget) -> String {
     )
 }
 
-pub(crate) fn rollup_bootstrap_js(names: &[PackageInfo], out_dir: &Path) -> String {
+pub(crate) fn rollup_bootstrap_js(names: &[&PackageInfo], out_dir: &Path) -> String {
     let mut output = String::new();
     for n in names {
         writeln!(
@@ -68,7 +68,7 @@ pub(crate) fn rollup_bootstrap_js(names: &[PackageInfo], out_dir: &Path) -> Stri
             path.push(p)
         }
         let name = n.get_package_name();
-        path.push(&format!("{}_bg.wasm", name));
+        path.push(format!("{}_bg.wasm", name));
         writeln!(
             output,
             r#"{}.default("{}").catch((e) => {{ console.log("Failed to load wasm file: {1}") }})"#,
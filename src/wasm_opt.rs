@@ -1,113 +1,467 @@
-use crate::Opt;
-use flate2::read::GzDecoder;
-use log::{error, info, trace};
-use std::{error::Error, fmt::Display, path::Path, process::Command};
+use crate::{download, error::Error, install, target_features, Opt};
+use log::{info, trace, warn};
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    process::Command,
+};
 use structopt::StructOpt;
-use tar::Archive;
-// Fixed version for consistent builds
+// Default fixed version for consistent builds
 // 97 has linux, windows & macos: only x84_64
-const BINDGEN_VERSION: &'static str = "version_97";
-const OUT_DIR: &'static str = "target/binaryen";
-const FINAL_PATH: &'static str = "target/binaryen/binaryen-version_97/bin/wasm-opt";
-const ARCH_X86_64: &'static str = "x86_64";
-
-// TODO: Is restricting this to x84_64 correct?
-enum Platform {
-    Linux,
-    Windows,
-    MacOS,
+const DEFAULT_VERSION: &str = "version_97";
+const RELEASES_URL: &str = "https://github.com/WebAssembly/binaryen/releases/download";
+const OUT_DIR: &str = "binaryen";
+const WASM_OPT: &str = "wasm-opt";
+/// (version, arch-os, sha256) of release archives pinned here, so a changed release is caught.
+/// Taken from the `.tar.gz.sha256` files published with each binaryen release. An archive that
+/// isn't pinned is only checked against the `.sha256` file next to it, which is no protection
+/// against a changed release, so this warns & `--sha256` should be used instead.
+// TODO: Pin version_97 for x86_64 linux, macos & windows, and the newer aarch64 releases
+const CHECKSUMS: &[(&str, &str, &str)] = &[];
+
+/// The hash in a `sha256sum` style file: `<sha256>  <file name>`
+fn parse_sha256_file(contents: &str) -> Option<String> {
+    let sha256 = contents.split_whitespace().next()?;
+    if sha256.len() == 64 && sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(sha256.to_ascii_lowercase())
+    } else {
+        None
+    }
+}
+
+fn known_sha256(version: &str, platform: &str) -> Option<&'static str> {
+    CHECKSUMS
+        .iter()
+        .find(|(v, p, _)| *v == version && *p == platform)
+        .map(|(_, _, sha256)| *sha256)
+}
+
+/// A host binaryen publishes releases for, named as in its archives: e.g. `binaryen-version_117-aarch64-linux.tar.gz`.
+/// Not every version has every host: version_97 & earlier are x86_64 only.
+#[derive(Debug, PartialEq)]
+struct Platform {
+    arch: &'static str,
+    os: &'static str,
 }
 
 impl Platform {
-    fn try_new() -> Result<Self, String> {
-        if std::env::consts::ARCH == ARCH_X86_64 {
-            match std::env::consts::OS {
-                "linux" => Ok(Self::Linux),
-                "macos" => Ok(Self::MacOS),
-                "windows" => Ok(Self::Windows),
-                _ => Err("Unsupported platform".to_string()),
-            }
-        } else {
-            Err("Prebuilt wasm-opt binaries are only available for x86_64".to_string())
-        }
+    fn try_new() -> Option<Self> {
+        Self::from(std::env::consts::ARCH, std::env::consts::OS)
+    }
+
+    fn from(arch: &str, os: &str) -> Option<Self> {
+        let os = match os {
+            "linux" => "linux",
+            "macos" => "macos",
+            "windows" => "windows",
+            _ => return None,
+        };
+        let arch = match (arch, os) {
+            ("x86_64", _) => "x86_64",
+            ("aarch64", "macos") => "arm64",
+            ("aarch64", "linux") => "aarch64",
+            _ => return None,
+        };
+        Some(Self { arch, os })
     }
 }
 
 impl Display for Platform {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Platform::Linux => f.write_str("linux"),
-            Platform::Windows => f.write_str("windows"),
-            Platform::MacOS => f.write_str("macos"),
-        }
+        write!(f, "{}-{}", self.arch, self.os)
+    }
+}
+
+fn levels(passes: &[String]) -> Vec<&str> {
+    passes
+        .iter()
+        .map(|p| p.as_str())
+        .filter(|p| p.starts_with("-O"))
+        .collect()
+}
+
+/// A --passes entry as the wasm-opt arg: `dce` or `--dce` is `--dce` & `Oz` or `-Oz` is `-Oz`
+fn parse_pass(pass: &str) -> Result<String, String> {
+    let pass = pass.trim();
+    let arg = if pass.starts_with('-') {
+        pass.to_string()
+    } else if pass.starts_with('O') {
+        format!("-{}", pass)
+    } else {
+        format!("--{}", pass)
+    };
+    if PASSES.contains(&arg.as_str()) {
+        Ok(arg)
+    } else {
+        Err(format!("unknown wasm-opt pass: {}", pass))
     }
 }
 
+fn wasm_opt_exe() -> String {
+    format!("{}{}", WASM_OPT, std::env::consts::EXE_SUFFIX)
+}
+
+/// `path` is relative to the top directory of the release: wasm-opt is in `bin` & any shared
+/// libraries it needs (e.g. libbinaryen) are in `lib`, or next to it on windows
+fn is_needed(path: &Path) -> bool {
+    let file = match path.file_name().and_then(|f| f.to_str()) {
+        Some(file) => file,
+        None => return false,
+    };
+    let shared_lib = file.ends_with(".dylib") || file.ends_with(".dll") || file.contains(".so");
+    match path.parent().and_then(|p| p.to_str()) {
+        Some("bin") => file == wasm_opt_exe() || shared_lib,
+        Some("lib") => shared_lib,
+        _ => false,
+    }
+}
+
+/// There is nothing to download so the user has to provide wasm-opt. `platform` is the host
+/// when binaryen releases for it, just not in this version: e.g. aarch64 before version_117.
+fn no_prebuilt(version: &str, platform: Option<&Platform>) -> Error {
+    let (host, newer) = match platform {
+        Some(platform) => (
+            platform.to_string(),
+            format!(
+                "Newer releases are built for {}: choose one with --binaryen-version, e.g. --binaryen-version version_117 \
+(see https://github.com/WebAssembly/binaryen/releases). Otherwise build",
+                platform
+            ),
+        ),
+        None => (
+            format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS),
+            String::from("Build"),
+        ),
+    };
+    Error::install(
+        WASM_OPT,
+        version,
+        format!(
+            "No prebuilt release for {}. {} binaryen from source (https://github.com/WebAssembly/binaryen#building) \
+& pass wasm-opt with --wasm-opt-path or put it on PATH, or install from a release archive with --archive",
+            host, newer
+        ),
+    )
+}
+
+include!(concat!(env!("OUT_DIR"), "/wasm_opt_flags.rs"));
+
 // TODO: Have to use long even due to clap always making `short` a single char.. file issue?
-// TODO: a macro that will generate this from the souce file file://./../wasm-opt.txt
-#[derive(StructOpt, Debug, Default)]
-#[allow(non_snake_case)]
+#[derive(StructOpt, Debug, Default, Clone)]
 pub(crate) struct WasmOpt {
-    /// execute default optimization passes
-    #[structopt(long = "O")]
-    O: bool,
-    /// execute no optimization passes
-    #[structopt(long = "O0")]
-    O0: bool,
-    /// execute -O1 optimization passes (quick&useful opts, useful for iteration builds)
-    #[structopt(long = "O1")]
-    O1: bool,
-    /// execute -O2 optimization passes (most opts, generally gets most perf)
-    #[structopt(long = "O2")]
-    O2: bool,
-    /// execute -O3 optimization passes (spends potentially a lot of time optimizing)
-    #[structopt(long = "O3")]
-    O3: bool,
-    /// execute -O4 optimization passes (also flatten the IR, which can take a lot more time and memory, but is useful on more nested / complex / less-optimized input)
-    #[structopt(long = "O4")]
-    O4: bool,
-    /// execute default optimization passes, focusing on code size
-    #[structopt(long = "Os")]
-    Os: bool,
-    /// execute default optimization passes, super-focusing on code size
-    #[structopt(long = "Oz")]
-    Oz: bool,
+    #[structopt(flatten)]
+    flags: WasmOptFlags,
+    /// passes & levels to run in this order, after any set with the flags above: e.g. --passes=dce,-Oz,strip-debug
+    #[structopt(
+        long,
+        use_delimiter = true,
+        allow_hyphen_values = true,
+        number_of_values = 1,
+        parse(try_from_str = parse_pass)
+    )]
+    passes: Vec<String>,
+    /// passes & levels for release builds, & every custom --profile, when none are given [default: -Oz,strip-debug]
+    #[structopt(
+        long,
+        use_delimiter = true,
+        allow_hyphen_values = true,
+        number_of_values = 1,
+        parse(try_from_str = parse_pass)
+    )]
+    release_passes: Vec<String>,
+    /// passes & levels for debug builds when none are given [default: -O1]
+    #[structopt(
+        long,
+        use_delimiter = true,
+        allow_hyphen_values = true,
+        number_of_values = 1,
+        parse(try_from_str = parse_pass)
+    )]
+    debug_passes: Vec<String>,
+    /// don't run wasm-opt for debug builds
+    #[structopt(long, conflicts_with = "debug-passes")]
+    skip_debug: bool,
+    /// run wasm-opt for debug builds even when the config sets skip-debug
+    #[structopt(long, conflicts_with = "skip-debug")]
+    no_skip_debug: bool,
+    /// turn off an option set in [package.metadata.wasm], e.g. --unset debuginfo
+    #[structopt(long, number_of_values = 1)]
+    unset: Vec<String>,
+    /// expected SHA-256 of the binaryen archive, checked instead of the published `.sha256` file
+    #[structopt(long)]
+    sha256: Option<String>,
+    /// binaryen release to install wasm-opt from [default: version_97]
+    #[structopt(long)]
+    binaryen_version: Option<String>,
+    /// base url to download binaryen releases from instead of GitHub
+    #[structopt(long, conflicts_with = "archive")]
+    mirror: Option<String>,
+    /// install from a local binaryen release .tar.gz instead of downloading it.
+    /// Must be the release for --binaryen-version.
+    #[structopt(long, parse(from_os_str))]
+    archive: Option<PathBuf>,
+    /// use this wasm-opt instead of installing one when it is --binaryen-version.
+    /// Otherwise one on PATH or an installed one is used.
+    #[structopt(long, parse(from_os_str))]
+    wasm_opt_path: Option<PathBuf>,
 }
 
 impl WasmOpt {
-    pub(crate) fn try_install(&self) -> Result<(), Box<dyn Error>> {
-        if !Path::new(FINAL_PATH).exists() {
-            let platform = Platform::try_new()?;
-            let name = format!(
-                "binaryen-{}-{}-{}.tar",
-                BINDGEN_VERSION, ARCH_X86_64, platform
-            );
-            let url = format!(
-                "http://github.com/WebAssembly/binaryen/releases/download/{}/{}.gz",
-                BINDGEN_VERSION, name
-            );
+    /// wasm-opt accepts more than one level, but only the last has any effect
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        for option in &self.unset {
+            if !WasmOptFlags::default().unset(option.trim_start_matches('-')) {
+                return Err(Error::Args(format!("unknown wasm-opt option: {}", option)));
+            }
+        }
+        let mut given = self.flags.levels();
+        given.extend(levels(&self.passes));
+        for mut levels in [
+            given,
+            levels(&self.release_passes),
+            levels(&self.debug_passes),
+        ] {
+            levels.sort_unstable();
+            levels.dedup();
+            if levels.len() > 1 {
+                return Err(Error::Args(format!(
+                    "conflicting wasm-opt optimization levels {}: use only one",
+                    levels.join(" ")
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill in anything not given on the command line from `config`.
+    /// Levels or passes on the command line replace those in the config, & --unset turns off
+    /// any of its options.
+    pub(crate) fn merge(&mut self, mut config: WasmOpt) {
+        for option in &self.unset {
+            config.flags.unset(option.trim_start_matches('-'));
+        }
+        if self.has_passes() {
+            config.flags.clear_levels();
+            config.passes.clear();
+        }
+        self.flags.merge(config.flags);
+        let vecs = [
+            (&mut self.passes, config.passes),
+            (&mut self.release_passes, config.release_passes),
+            (&mut self.debug_passes, config.debug_passes),
+        ];
+        for (cli, config) in vecs {
+            if cli.is_empty() {
+                *cli = config;
+            }
+        }
+        if !self.no_skip_debug {
+            self.skip_debug |= config.skip_debug;
+        }
+        // Where to get binaryen from on the command line replaces both in the config
+        if self.mirror.is_none() && self.archive.is_none() {
+            self.mirror = config.mirror;
+            self.archive = config.archive;
+        }
+        self.sha256 = self.sha256.take().or(config.sha256);
+        self.binaryen_version = self.binaryen_version.take().or(config.binaryen_version);
+        self.wasm_opt_path = self.wasm_opt_path.take().or(config.wasm_opt_path);
+    }
+
+    /// Whether wasm-opt is run at all for this build
+    pub(crate) fn runs_for(&self, opt: &Opt) -> bool {
+        opt.is_release() || !self.skip_debug || self.has_passes()
+    }
+
+    fn has_passes(&self) -> bool {
+        !self.flags.levels().is_empty() || !self.passes.is_empty()
+    }
+
+    /// The flags, then the passes in the order they run
+    pub(crate) fn pass_args(&self, opt: &Opt) -> Vec<String> {
+        let pipeline = self.pipeline(opt);
+        if !self.has_passes() {
+            trace!("wasm-opt using profile passes: {}", pipeline.join(" "));
+        }
+        let mut args = self.flags.args();
+        args.extend(pipeline);
+        args
+    }
 
-            info!("Trying to download wasm-opt from: {}", url);
-            let client = reqwest::blocking::Client::new();
-            // TODO: How many retries?
-            let data = match client.get(&url).send().and_then(|r| r.bytes()) {
-                Ok(data) => data,
-                Err(e) => {
-                    error!("First request failed with: '{}'. Retrying...", e);
-                    client.get(&url).send()?.bytes()?
-                }
-            };
-
-            let decompressed = GzDecoder::new(&*data);
-            let mut archive = Archive::new(decompressed);
-            // TODO: Just get wasm-opt?
-            archive.unpack(OUT_DIR)?;
-            info!("wasm-opt installed at: {}", FINAL_PATH);
-            Ok(())
+    /// The passes given with --passes, otherwise when no level was set either those for the profile
+    fn pipeline(&self, opt: &Opt) -> Vec<String> {
+        if self.has_passes() {
+            self.passes.clone()
+        } else if opt.is_release() {
+            if self.release_passes.is_empty() {
+                vec![String::from("-Oz"), String::from("--strip-debug")]
+            } else {
+                self.release_passes.clone()
+            }
+        } else if self.debug_passes.is_empty() {
+            vec![String::from("-O1")]
+        } else {
+            self.debug_passes.clone()
+        }
+    }
+
+    fn version(&self) -> &str {
+        self.binaryen_version.as_deref().unwrap_or(DEFAULT_VERSION)
+    }
+
+    /// Each binaryen version is kept separately: <tools_dir>/binaryen/<version>
+    fn install_dir(&self, tools_dir: &Path) -> PathBuf {
+        let mut path = tools_dir.join(OUT_DIR);
+        path.push(self.version());
+        path
+    }
+
+    fn final_path(&self, tools_dir: &Path) -> PathBuf {
+        self.final_path_in(&self.install_dir(tools_dir))
+    }
+
+    fn final_path_in(&self, install_dir: &Path) -> PathBuf {
+        let mut path = install_dir.to_path_buf();
+        path.push(format!("binaryen-{}", self.version()));
+        path.push("bin");
+        path.push(wasm_opt_exe());
+        path
+    }
+
+    fn archive_url(&self, name: &str) -> String {
+        let releases = self.mirror.as_deref().unwrap_or(RELEASES_URL);
+        format!(
+            "{}/{}/{}",
+            releases.trim_end_matches('/'),
+            self.version(),
+            name
+        )
+    }
+
+    /// `wasm-opt --version` prints `wasm-opt version 97 (version_97)` or just `version_97`
+    fn system_wasm_opt(&self) -> Option<PathBuf> {
+        let version = self.version();
+        let number = version.trim_start_matches("version_");
+        install::find_system_tool(
+            self.wasm_opt_path.as_deref(),
+            WASM_OPT,
+            &[version, number],
+            |_| true,
+        )
+    }
+
+    /// Returns the wasm-opt to run: one already installed at the right version is preferred
+    pub(crate) fn try_install(&self, tools_dir: &Path) -> Result<PathBuf, Error> {
+        if let Some(bin) = self.system_wasm_opt() {
+            info!("Using wasm-opt at: {}", bin.display());
+            return Ok(bin);
+        }
+        let install_dir = self.install_dir(tools_dir);
+        let fresh = install::install_once(
+            &install_dir,
+            |dir| self.final_path_in(dir).exists() && install::verify_manifest(dir),
+            |tmp| {
+                let data = match &self.archive {
+                    Some(archive) => self.read_archive(archive)?,
+                    None => self.download_archive()?,
+                };
+                self.unpack(&data, tmp)
+            },
+        )?;
+        if fresh {
+            info!(
+                "wasm-opt installed at: {}",
+                self.final_path(tools_dir).display()
+            );
         } else {
             info!("wasm-opt already installed");
-            Ok(())
         }
+        Ok(self.final_path(tools_dir))
+    }
+
+    /// Only wasm-opt & the shared libraries it links to are unpacked from the release
+    fn unpack(&self, data: &[u8], install_dir: &Path) -> Result<(), Error> {
+        let top = format!("binaryen-{}", self.version());
+        let files = download::unpack_matching(data, install_dir, |path| {
+            path.strip_prefix(&top).map(is_needed).unwrap_or(false)
+        })?;
+        let final_path = self.final_path_in(install_dir);
+        if !final_path.exists() {
+            return Err(Error::install(
+                WASM_OPT,
+                self.version(),
+                format!("archive does not contain {}/bin/{}", top, wasm_opt_exe()),
+            ));
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&final_path, std::fs::Permissions::from_mode(0o755))
+                .map_err(|e| Error::io(&final_path, e))?;
+        }
+        trace!("Unpacked: {:?}", files);
+        install::write_manifest(install_dir, &files)
+    }
+
+    /// A local archive is trusted unless --sha256 is given
+    fn read_archive(&self, archive: &Path) -> Result<Vec<u8>, Error> {
+        info!("Installing wasm-opt from: {}", archive.display());
+        let data = std::fs::read(archive).map_err(|e| Error::io(archive, e))?;
+        if let Some(sha256) = &self.sha256 {
+            download::verify_sha256(&archive.display().to_string(), &data, sha256)?;
+        }
+        Ok(data)
+    }
+
+    fn download_archive(&self) -> Result<Vec<u8>, Error> {
+        let platform = Platform::try_new().ok_or_else(|| no_prebuilt(self.version(), None))?;
+        let name = format!("binaryen-{}-{}.tar.gz", self.version(), platform);
+        let url = self.archive_url(&name);
+
+        info!("Trying to download wasm-opt from: {}", url);
+        let data = match download::fetch(&url) {
+            Err(Error::Download { source, .. })
+                if source.status() == Some(reqwest::StatusCode::NOT_FOUND) =>
+            {
+                Err(no_prebuilt(self.version(), Some(&platform)))
+            }
+            data => data,
+        }?;
+        let expected = self.expected_sha256(&url, &platform.to_string())?;
+        download::verify_sha256(&url, &data, &expected)?;
+        Ok(data)
+    }
+
+    /// `--sha256` if given, then the pinned checksum, otherwise the `.sha256` file published
+    /// next to the archive: that comes from the same place so only catches a broken download
+    fn expected_sha256(&self, url: &str, platform: &str) -> Result<String, Error> {
+        if let Some(sha256) = &self.sha256 {
+            return Ok(sha256.clone());
+        }
+        if let Some(sha256) = known_sha256(self.version(), platform) {
+            return Ok(sha256.to_string());
+        }
+        let sha256_url = format!("{}.sha256", url);
+        warn!(
+            "No checksum is pinned for {} {}: only checking against {}, which is served from the same place as the archive.\n\
+Pass a checksum you trust with --sha256 to verify it",
+            self.version(),
+            platform,
+            sha256_url
+        );
+        let published = download::fetch(&sha256_url)
+            .ok()
+            .and_then(|data| parse_sha256_file(&String::from_utf8_lossy(&data)));
+        published.ok_or_else(|| {
+            Error::install(
+                WASM_OPT,
+                self.version(),
+                format!(
+                    "No known checksum for {} & {} could not be read: pass it with --sha256",
+                    url, sha256_url
+                ),
+            )
+        })
     }
 
     fn file_size(raw_size: u64) -> String {
@@ -123,108 +477,445 @@ impl WasmOpt {
             format!("{:.2} MB", raw_size as f64 / mb as f64)
         }
     }
-    // TODO: What should the defaults be? What should release trigger?
+    /// Any feature flag stops wasm-opt reading the target_features section itself,
+    /// so every feature the wasm was built with is passed explicitly
+    fn feature_flags(wasm: &Path, opt: &Opt) -> Result<Vec<&'static str>, Error> {
+        let data = std::fs::read(wasm).map_err(|e| Error::io(wasm, e))?;
+        let mut flags: Vec<&str> = match target_features::read(&data) {
+            Some(features) => {
+                trace!("{} target features: {:?}", wasm.display(), features);
+                features
+                    .iter()
+                    .filter_map(|f| target_features::wasm_opt_flag(f))
+                    .collect()
+            }
+            None => {
+                trace!("{} has no target_features section", wasm.display());
+                Vec::new()
+            }
+        };
+        if opt.reference_types {
+            flags.push("--enable-reference-types");
+        }
+        flags.sort_unstable();
+        flags.dedup();
+        Ok(flags)
+    }
+
     // bin/wasm-opt [.wasm or .wat file] [options] [passes]
-    pub(crate) fn try_run(&self, wasm: &Path, opt: &Opt) -> Result<(), Box<dyn Error>> {
-        let mut cmd = Command::new(FINAL_PATH);
-        let wasm_file = std::fs::File::open(wasm)?;
+    /// `bin` is the wasm-opt returned by `try_install`
+    pub(crate) fn try_run(&self, bin: &Path, wasm: &Path, opt: &Opt) -> Result<(), Error> {
+        let mut cmd = Command::new(bin);
+        let wasm_file = std::fs::File::open(wasm).map_err(|e| Error::io(wasm, e))?;
+        let file_size = || {
+            wasm_file
+                .metadata()
+                .map(|m| m.len())
+                .map_err(|e| Error::io(wasm, e))
+        };
 
         // [WASM File]
         cmd.arg(wasm);
 
         // [OPTIONS]
-        cmd.args(&["--output", &wasm.display().to_string()]);
+        cmd.args(["--output", &wasm.display().to_string()]);
 
-        if opt.reference_types {
-            cmd.arg("--enable-reference-types");
-        }
+        cmd.args(Self::feature_flags(wasm, opt)?);
 
         // [PASSES]
-        let mut set = false;
-        if self.O0 {
-            cmd.arg("-O0");
-            set = true;
-        }
-        if self.O1 {
-            cmd.arg("-O1");
-            set = true;
-        }
-        if self.O2 {
-            cmd.arg("-O2");
-            set = true;
-        }
-        if self.O3 {
-            cmd.arg("-O3");
-            set = true;
-        }
-        if self.O4 {
-            cmd.arg("-O4");
-            set = true;
-        }
-        if self.Os {
-            cmd.arg("-Os");
-            set = true;
-        }
-        if self.Oz {
-            cmd.arg("-Oz");
-            set = true;
-        }
-        if self.O || !set {
-            trace!("wasm-opt using default optimization passes");
-            cmd.arg("-O");
-        }
+        cmd.args(self.pass_args(opt));
 
-        let original_file_size = wasm_file.metadata()?.len();
-        let run = crate::run_command(cmd, opt.quiet);
+        let original_file_size = file_size()?;
+        crate::run_command(cmd, opt.quiet).map_err(|source| Error::WasmOpt {
+            wasm: wasm.to_path_buf(),
+            source,
+        })?;
 
-        match run {
-            Ok(_) => {
-                let final_file_size = wasm_file.metadata()?.len();
-                info!(
-                    "Ran wasm-opt for {}
+        let final_file_size = file_size()?;
+        info!(
+            "Ran wasm-opt for {}
 Orignal size: {}
   Final size: {}
-   Reduction: {:.1} % [{}]",
-                    wasm.display(),
-                    Self::file_size(original_file_size),
-                    Self::file_size(final_file_size),
-                    ((original_file_size - final_file_size) as f64 / original_file_size as f64)
-                        * 100f64,
-                    Self::file_size(original_file_size - final_file_size),
-                );
-            }
-            Err(e) => error!(
-                "Failed to run wasm-opt for {}
 {}",
-                wasm.display(),
-                e
-            ),
-        }
-
+            wasm.display(),
+            Self::file_size(original_file_size),
+            Self::file_size(final_file_size),
+            Self::size_change(original_file_size, final_file_size),
+        );
         Ok(())
     }
+
+    /// Passes such as --debuginfo can make the wasm bigger
+    fn size_change(original: u64, final_size: u64) -> String {
+        let (label, change) = if final_size <= original {
+            ("Reduction", original - final_size)
+        } else {
+            ("Growth", final_size - original)
+        };
+        format!(
+            "{:>12}: {:.1} % [{}]",
+            label,
+            change as f64 / original.max(1) as f64 * 100f64,
+            Self::file_size(change)
+        )
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::download::tests::tarball;
     #[test]
     fn download_and_run() {
-        std::fs::remove_dir_all(OUT_DIR).unwrap_or(());
+        let tools_dir = Path::new("target/cargo-wasm");
         let opts = Opt::default();
         let wasm_opt = WasmOpt::default();
+        std::fs::remove_dir_all(wasm_opt.install_dir(tools_dir)).unwrap_or(());
         let test_wasm = Path::new("test_crates/test.wasm");
         let template = Path::new("test_crates/_test.wasm");
         std::fs::copy(template, test_wasm).unwrap();
         let template = std::fs::File::open(template).unwrap();
         let test_wasm_file = std::fs::File::open(test_wasm).unwrap();
-        wasm_opt.try_install().unwrap();
-        wasm_opt.try_run(test_wasm, &opts).unwrap();
+        let bin = wasm_opt.try_install(tools_dir).unwrap();
+        wasm_opt.try_run(&bin, test_wasm, &opts).unwrap();
         // Check smaller
         assert!(test_wasm_file.metadata().unwrap().len() < template.metadata().unwrap().len());
         std::fs::remove_file(test_wasm).unwrap();
     }
 
+    #[test]
+    fn needs_a_checksum() {
+        let wasm_opt = WasmOpt::default();
+        // Nothing listens here, so there is no published checksum either
+        let e = wasm_opt
+            .expected_sha256("http://127.0.0.1:9/binaryen.tar.gz", "plan9")
+            .unwrap_err();
+        assert!(matches!(e, Error::Install { .. }));
+
+        let wasm_opt = WasmOpt {
+            sha256: Some(String::from("abc")),
+            ..WasmOpt::default()
+        };
+        assert_eq!(
+            wasm_opt
+                .expected_sha256("http://127.0.0.1:9/binaryen.tar.gz", "plan9")
+                .unwrap(),
+            "abc"
+        );
+
+        let abc = "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD";
+        assert_eq!(
+            parse_sha256_file(&format!(
+                "{}  binaryen-version_97-x86_64-linux.tar.gz\n",
+                abc
+            )),
+            Some(abc.to_ascii_lowercase())
+        );
+        assert_eq!(parse_sha256_file("<html>Not Found</html>"), None);
+        assert_eq!(parse_sha256_file(""), None);
+    }
+
+    #[test]
+    fn versioned_paths() {
+        let tools_dir = Path::new("tools");
+        let wasm_opt = WasmOpt::default();
+        assert_eq!(
+            wasm_opt.final_path(tools_dir),
+            Path::new("tools/binaryen/version_97/binaryen-version_97/bin/wasm-opt")
+        );
+        assert_eq!(
+            wasm_opt.archive_url("a.tar.gz"),
+            "https://github.com/WebAssembly/binaryen/releases/download/version_97/a.tar.gz"
+        );
+
+        let wasm_opt = WasmOpt {
+            binaryen_version: Some(String::from("version_98")),
+            mirror: Some(String::from("https://artifacts.internal/binaryen/")),
+            ..WasmOpt::default()
+        };
+        assert_eq!(
+            wasm_opt.final_path(tools_dir),
+            Path::new("tools/binaryen/version_98/binaryen-version_98/bin/wasm-opt")
+        );
+        assert_eq!(
+            wasm_opt.archive_url("a.tar.gz"),
+            "https://artifacts.internal/binaryen/version_98/a.tar.gz"
+        );
+    }
+
+    #[test]
+    fn installs_from_local_archive() {
+        let dir = Path::new("target/test-local-archive");
+        std::fs::remove_dir_all(dir).unwrap_or(());
+        std::fs::create_dir_all(dir).unwrap();
+        let archive = dir.join("binaryen.tar.gz");
+        let data = tarball(&[("binaryen-vendored/bin/wasm-opt", &[], 0o755)]);
+        std::fs::write(&archive, &data).unwrap();
+
+        let tools_dir = dir.join("tools");
+        let wasm_opt = WasmOpt {
+            binaryen_version: Some(String::from("vendored")),
+            archive: Some(archive.clone()),
+            sha256: Some(String::from("0000")),
+            ..WasmOpt::default()
+        };
+        let e = wasm_opt.try_install(&tools_dir).unwrap_err();
+        assert!(matches!(e, Error::Checksum { .. }));
+
+        let wasm_opt = WasmOpt {
+            sha256: Some(download::sha256(&data)),
+            ..wasm_opt
+        };
+        wasm_opt.try_install(&tools_dir).unwrap();
+        assert!(wasm_opt.final_path(&tools_dir).exists());
+
+        // The archive has to match the version
+        let wasm_opt = WasmOpt {
+            binaryen_version: Some(String::from("version_97")),
+            ..wasm_opt
+        };
+        let e = wasm_opt.try_install(&tools_dir).unwrap_err();
+        assert!(matches!(e, Error::Install { .. }));
+    }
+
+    #[test]
+    fn release_hosts() {
+        let name = |arch, os| Platform::from(arch, os).map(|p| p.to_string());
+        assert_eq!(name("x86_64", "linux").unwrap(), "x86_64-linux");
+        assert_eq!(name("x86_64", "windows").unwrap(), "x86_64-windows");
+        assert_eq!(name("aarch64", "linux").unwrap(), "aarch64-linux");
+        assert_eq!(name("aarch64", "macos").unwrap(), "arm64-macos");
+        assert_eq!(name("aarch64", "windows"), None);
+        assert_eq!(name("x86_64", "freebsd"), None);
+
+        let e = no_prebuilt(DEFAULT_VERSION, None).to_string();
+        assert!(e.contains("--wasm-opt-path") && !e.contains("--binaryen-version"));
+        let arm = Platform::from("aarch64", "linux");
+        let e = no_prebuilt(DEFAULT_VERSION, arm.as_ref()).to_string();
+        assert!(e.contains("aarch64-linux") && e.contains("--binaryen-version"));
+        assert!(e.contains("--wasm-opt-path"));
+    }
+
+    #[test]
+    fn only_unpacks_wasm_opt() {
+        let exe = wasm_opt_exe();
+        let entries = [
+            format!("binaryen-version_97/bin/{}", exe),
+            String::from("binaryen-version_97/bin/wasm2js"),
+            String::from("binaryen-version_97/lib/libbinaryen.so"),
+            String::from("binaryen-version_97/lib/libbinaryen.a"),
+            String::from("binaryen-version_97/include/binaryen-c.h"),
+        ];
+        let entries: Vec<(&str, &[u8], u32)> = entries
+            .iter()
+            .map(|path| (path.as_str(), &b"data"[..], 0o644))
+            .collect();
+        let data = tarball(&entries);
+
+        let dir = Path::new("target/test-unpack-wasm-opt");
+        std::fs::remove_dir_all(dir).unwrap_or(());
+        let wasm_opt = WasmOpt::default();
+        wasm_opt.unpack(&data, dir).unwrap();
+
+        let top = dir.join("binaryen-version_97");
+        let mut unpacked: Vec<PathBuf> = ["bin", "lib"]
+            .iter()
+            .flat_map(|d| std::fs::read_dir(top.join(d)).unwrap())
+            .map(|e| e.unwrap().path())
+            .collect();
+        unpacked.sort();
+        assert_eq!(
+            unpacked,
+            vec![top.join("bin").join(&exe), top.join("lib/libbinaryen.so")]
+        );
+        assert!(!top.join("include").exists());
+        assert!(install::verify_manifest(dir));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(top.join("bin").join(&exe))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o755);
+        }
+    }
+
+    #[test]
+    fn passes_flags_through() {
+        let wasm_opt = WasmOpt::from_iter(&[
+            "wasm-opt",
+            "--O3",
+            "--enable-simd",
+            "--debuginfo",
+            "--shrink-level",
+            "2",
+            "--pass-arg",
+            "asyncify-imports@env.sleep",
+            "--pass-arg",
+            "a@b",
+            "--dce",
+            "--output-source-map",
+            "out.map",
+        ]);
+        assert_eq!(wasm_opt.flags.levels(), vec!["-O3"]);
+        assert_eq!(
+            wasm_opt.flags.args(),
+            vec![
+                "--enable-simd",
+                "--pass-arg",
+                "asyncify-imports@env.sleep",
+                "--pass-arg",
+                "a@b",
+                "-O3",
+                "--shrink-level",
+                "2",
+                "--debuginfo",
+                "--dce",
+                "--output-source-map",
+                "out.map",
+            ]
+        );
+        assert!(WasmOpt::default().flags.levels().is_empty());
+        assert!(WasmOpt::from_iter_safe(&["wasm-opt", "--shrink-level", "two"]).is_err());
+        assert!(WasmOpt::from_iter_safe(&["wasm-opt", "--output", "a.wasm"]).is_err());
+        assert!(WasmOpt::from_iter_safe(&["wasm-opt", "--emit-text"]).is_err());
+        assert!(WasmOpt::from_iter_safe(&["wasm-opt", "--print-full"]).is_err());
+        // Options taking a value don't swallow the next pass
+        let wasm_opt = WasmOpt::from_iter(&[
+            "wasm-opt",
+            "--output-source-map-url",
+            "app.wasm.map",
+            "--emit-js-wrapper",
+            "wrapper.js",
+            "--Oz",
+        ]);
+        assert_eq!(
+            wasm_opt.flags.args(),
+            vec![
+                "-Oz",
+                "--emit-js-wrapper",
+                "wrapper.js",
+                "--output-source-map-url",
+                "app.wasm.map"
+            ]
+        );
+    }
+
+    #[test]
+    fn ordered_passes() {
+        let wasm_opt = WasmOpt::from_iter(&["wasm-opt", "--passes", "dce,-Oz,strip-debug"]);
+        assert_eq!(wasm_opt.passes, vec!["--dce", "-Oz", "--strip-debug"]);
+        let wasm_opt = WasmOpt::from_iter(&["wasm-opt", "--passes=--vacuum,O", "--passes", "-O"]);
+        assert_eq!(wasm_opt.passes, vec!["--vacuum", "-O", "-O"]);
+        assert!(wasm_opt.validate().is_ok());
+        assert!(WasmOpt::from_iter_safe(&["wasm-opt", "--passes", "dce,not-a-pass"]).is_err());
+        // Options that aren't passes
+        for option in &["emit-text", "enable-simd", "quiet", "debuginfo", "print"] {
+            assert!(parse_pass(option).is_err(), "{} is not a pass", option);
+        }
+    }
+
+    #[test]
+    fn conflicting_levels() {
+        let conflict = |args: &[&str]| {
+            let wasm_opt = WasmOpt::from_iter(args);
+            matches!(wasm_opt.validate(), Err(Error::Args(_)))
+        };
+        assert!(conflict(&["wasm-opt", "--O3", "--Oz"]));
+        assert!(conflict(&["wasm-opt", "--O3", "--passes=dce,-Os"]));
+        assert!(!conflict(&["wasm-opt", "--Oz", "--passes=dce,-Oz"]));
+        assert!(!conflict(&["wasm-opt", "--passes=dce"]));
+        assert!(conflict(&["wasm-opt", "--release-passes=-O3,-Oz"]));
+        assert!(!conflict(&["wasm-opt", "--O3", "--release-passes=-Oz"]));
+    }
+
+    #[test]
+    fn enables_built_features() {
+        let wasm = Path::new("target/test-feature-flags.wasm");
+        let mut payload = b"\x0ftarget_features\x02".to_vec();
+        payload.extend_from_slice(b"+\x07simd128+\x0bbulk-memory");
+        let mut data = b"\0asm\x01\0\0\0\0".to_vec();
+        data.push(payload.len() as u8);
+        data.extend(payload);
+        std::fs::write(wasm, data).unwrap();
+
+        let opt = Opt {
+            reference_types: true,
+            ..Opt::default()
+        };
+        assert_eq!(
+            WasmOpt::feature_flags(wasm, &opt).unwrap(),
+            vec![
+                "--enable-bulk-memory",
+                "--enable-reference-types",
+                "--enable-simd"
+            ]
+        );
+        let mvp = Path::new("test_crates/_test.wasm");
+        assert!(WasmOpt::feature_flags(mvp, &Opt::default())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn profile_defaults() {
+        let debug = Opt::default();
+        let release = Opt {
+            release: true,
+            ..Opt::default()
+        };
+        let pipeline = |args: &[&str], opt: &Opt| WasmOpt::from_iter(args).pipeline(opt);
+
+        assert_eq!(pipeline(&["wasm-opt"], &debug), vec!["-O1"]);
+        assert_eq!(
+            pipeline(&["wasm-opt"], &release),
+            vec!["-Oz", "--strip-debug"]
+        );
+        assert_eq!(
+            pipeline(&["wasm-opt", "--release-passes=-O3"], &release),
+            vec!["-O3"]
+        );
+        assert_eq!(
+            pipeline(&["wasm-opt", "--debug-passes=dce"], &debug),
+            vec!["--dce"]
+        );
+        // Anything given explicitly wins
+        assert!(pipeline(&["wasm-opt", "--O2"], &release).is_empty());
+        assert_eq!(
+            pipeline(&["wasm-opt", "--passes=vacuum"], &release),
+            vec!["--vacuum"]
+        );
+
+        let skip = WasmOpt::from_iter(&["wasm-opt", "--skip-debug"]);
+        assert!(!skip.runs_for(&debug));
+        assert!(skip.runs_for(&release));
+        let custom = Opt {
+            profile: Some(String::from("wasm-release")),
+            ..Opt::default()
+        };
+        assert!(skip.runs_for(&custom));
+        assert_eq!(
+            pipeline(&["wasm-opt"], &custom),
+            vec!["-Oz", "--strip-debug"]
+        );
+        assert!(WasmOpt::from_iter(&["wasm-opt", "--skip-debug", "--O2"]).runs_for(&debug));
+        assert!(WasmOpt::from_iter(&["wasm-opt"]).runs_for(&debug));
+    }
+
+    #[test]
+    fn check_size_change() {
+        assert_eq!(
+            WasmOpt::size_change(2048, 1024),
+            "   Reduction: 50.0 % [1.00 KiB]"
+        );
+        assert_eq!(
+            WasmOpt::size_change(1000, 1100),
+            "      Growth: 10.0 % [100 bytes]"
+        );
+        assert_eq!(WasmOpt::size_change(0, 0), "   Reduction: 0.0 % [0 bytes]");
+    }
+
     #[test]
     fn check_file_size() {
         assert_eq!(WasmOpt::file_size(0), "0 bytes");
@@ -0,0 +1,182 @@
+use crate::{error::Error, BindgenPackages, Opt, PackageInfo};
+use log::{error, info, trace};
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use std::{collections::BTreeSet, path::PathBuf, sync::mpsc::channel, time::Duration};
+
+// Editors tend to write several files (or the same file several times) on save
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+fn changed_path(event: DebouncedEvent) -> Option<PathBuf> {
+    match event {
+        DebouncedEvent::Create(p)
+        | DebouncedEvent::Write(p)
+        | DebouncedEvent::Remove(p)
+        | DebouncedEvent::Rename(_, p) => Some(p),
+        _ => None,
+    }
+}
+
+impl BindgenPackages {
+    fn workspace_manifest(&self) -> PathBuf {
+        self.workspace_root.join("Cargo.toml")
+    }
+
+    /// The packages with either a file in `src` or their `Cargo.toml` in `changed`:
+    /// every package when it is the workspace's `Cargo.toml`
+    fn affected(&self, changed: &BTreeSet<PathBuf>) -> Vec<&PackageInfo> {
+        let workspace = changed.contains(&self.workspace_manifest());
+        self.packages
+            .iter()
+            .filter(|p| {
+                let src = p.package_dir().join("src");
+                workspace
+                    || changed
+                        .iter()
+                        .any(|c| c.starts_with(&src) || c == &p.package.manifest_path)
+            })
+            .collect()
+    }
+
+    /// A changed `Cargo.toml` can change the wasm-bindgen version or the config
+    fn manifest_changed(&self, changed: &BTreeSet<PathBuf>) -> bool {
+        changed.contains(&self.workspace_manifest())
+            || self
+                .packages
+                .iter()
+                .any(|p| changed.contains(&p.package.manifest_path))
+    }
+
+    /// Read again from `cargo metadata`, installing any new wasm-bindgen-cli
+    fn reload(&self, opt: &Opt) -> Result<Self, Error> {
+        let mut bp = BindgenPackages::new(self.cargo.clone(), &opt.features)?;
+        bp.select(&opt.selection)?;
+        bp.package_opts(&bp.packages, opt)?;
+        bp.install_wasm_bindgen_cli(opt.quiet, opt.wasm_bindgen_path.as_deref())?;
+        Ok(bp)
+    }
+
+    /// `src` of each package, & the directories the `Cargo.toml` files are in. The manifests are
+    /// found by path in the events: a watch on the file itself is lost when an editor saves by
+    /// renaming over it.
+    fn watch_paths(&self) -> BTreeSet<(PathBuf, bool)> {
+        let mut paths = BTreeSet::new();
+        paths.insert((self.workspace_root.clone(), false));
+        for p in &self.packages {
+            paths.insert((p.package_dir().join("src"), true));
+            paths.insert((p.package_dir().to_path_buf(), false));
+        }
+        paths
+    }
+}
+
+/// Adds any of `bindgen_packages` paths not in `watched`
+fn add_watches(
+    watcher: &mut RecommendedWatcher,
+    bindgen_packages: &BindgenPackages,
+    watched: &mut BTreeSet<(PathBuf, bool)>,
+) -> Result<(), Error> {
+    for (path, recursive) in bindgen_packages.watch_paths() {
+        if !watched.contains(&(path.clone(), recursive)) {
+            let mode = if recursive {
+                RecursiveMode::Recursive
+            } else {
+                RecursiveMode::NonRecursive
+            };
+            watcher.watch(&path, mode)?;
+            watched.insert((path, recursive));
+        }
+    }
+    Ok(())
+}
+
+/// Blocks, rebuilding the affected packages after each burst of changes.
+/// The packages are reloaded first when a `Cargo.toml` changed.
+/// `on_rebuild` is called after every successful rebuild.
+pub(crate) fn watch(
+    bindgen_packages: &BindgenPackages,
+    opt: &Opt,
+    on_rebuild: impl Fn(),
+) -> Result<(), Error> {
+    let (tx, rx) = channel();
+    let mut watcher = notify::watcher(tx, DEBOUNCE)?;
+    let mut bindgen_packages = bindgen_packages.clone();
+    let mut watched = BTreeSet::new();
+    add_watches(&mut watcher, &bindgen_packages, &mut watched)?;
+    info!("Watching for changes...");
+
+    while let Ok(event) = rx.recv() {
+        let mut changed = BTreeSet::new();
+        changed.extend(changed_path(event));
+        while let Ok(event) = rx.recv_timeout(DEBOUNCE) {
+            changed.extend(changed_path(event));
+        }
+        trace!("Changed: {:?}", changed);
+
+        if bindgen_packages.manifest_changed(&changed) {
+            info!("Cargo.toml changed: reloading packages");
+            match bindgen_packages.reload(opt) {
+                Ok(reloaded) => {
+                    bindgen_packages = reloaded;
+                    add_watches(&mut watcher, &bindgen_packages, &mut watched)?;
+                }
+                // Keep the old packages until the manifest is fixed
+                Err(e) => {
+                    error!("{}", e);
+                    info!("Watching for changes...");
+                    continue;
+                }
+            }
+        }
+
+        let affected = bindgen_packages.affected(&changed);
+        if !affected.is_empty() {
+            for p in &affected {
+                info!("Change detected in {}", p.package.name);
+            }
+            match bindgen_packages.rebuild(&affected, opt) {
+                Ok(_) => on_rebuild(),
+                Err(e) => error!("{}", e),
+            }
+            info!("Watching for changes...");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::packages_in;
+
+    #[test]
+    fn only_affected_packages() {
+        let bp = packages_in("test_crates/web/workspace");
+        let root = bp.workspace_root.clone();
+        let names = |changed: &[PathBuf]| -> Vec<String> {
+            let changed = changed.iter().cloned().collect();
+            bp.affected(&changed)
+                .iter()
+                .map(|p| p.package.name.clone())
+                .collect()
+        };
+
+        assert_eq!(names(&[root.join("crate1/src/lib.rs")]), vec!["crate1"]);
+        assert_eq!(names(&[root.join("src/lib.rs")]), vec!["workspace"]);
+        assert_eq!(names(&[root.join("crate2/Cargo.toml")]), vec!["crate2"]);
+        assert!(names(&[root.join("dist/index.html")]).is_empty());
+        assert!(names(&[root.join("crate2/README.md")]).is_empty());
+        assert_eq!(names(&[root.join("Cargo.toml")]).len(), bp.packages.len());
+
+        let manifest_changed =
+            |path: PathBuf| bp.manifest_changed(&Some(path).into_iter().collect());
+        assert!(manifest_changed(root.join("crate1/Cargo.toml")));
+        assert!(manifest_changed(root.join("Cargo.toml")));
+        assert!(!manifest_changed(root.join("crate1/src/lib.rs")));
+
+        // The manifests are found through their directories
+        let paths = bp.watch_paths();
+        assert!(paths.contains(&(root.join("crate1"), false)));
+        assert!(paths.contains(&(root.join("crate1/src"), true)));
+        assert!(!paths.iter().any(|(p, _)| p.ends_with("Cargo.toml")));
+    }
+}
@@ -0,0 +1,9 @@
+// Only uses wasm-bindgen with the `web` feature
+#[cfg(feature = "web")]
+use wasm_bindgen::prelude::*;
+
+#[cfg(feature = "web")]
+#[wasm_bindgen]
+pub fn add(a: u32, b: u32) -> u32 {
+    a + b
+}